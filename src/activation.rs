@@ -0,0 +1,250 @@
+use bevy::prelude::*;
+
+use crate::{
+    level::{
+        ActivationEvent, ActiveElement, DeactivateElement, NamedElement,
+        OwningLevel,
+    },
+    states::States,
+    world_state::WorldState,
+};
+
+pub struct ActivationPlugin;
+
+impl Plugin for ActivationPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ActivationRegistry>()
+            .add_system(queue_activations)
+            .add_system(apply_activations_to_new_elements)
+            .add_system_set(
+                SystemSet::on_enter(States::InGame)
+                    .with_system(warn_unresolved_activations),
+            )
+            .add_system_set(
+                SystemSet::on_enter(States::Menu)
+                    .with_system(reset_activation_registry),
+            );
+    }
+}
+
+/// Groups an element belongs to, from the LDtk `Groups` field. Activation
+/// targets starting with `@` match on these instead of the `EntityId`.
+#[derive(Component)]
+pub struct ElementGroups(pub Vec<String>);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ElementPattern {
+    Exact(String),
+    Wildcard(String),
+    Group(String),
+}
+
+impl ElementPattern {
+    pub fn parse(target: &str) -> Self {
+        let target = target.trim();
+        if let Some(group) = target.strip_prefix('@') {
+            Self::Group(group.to_string())
+        } else if target.contains('*') {
+            Self::Wildcard(target.to_string())
+        } else {
+            Self::Exact(target.to_string())
+        }
+    }
+
+    pub fn matches(
+        &self,
+        name: Option<&NamedElement>,
+        groups: Option<&ElementGroups>,
+    ) -> bool {
+        match self {
+            Self::Exact(target) => name.map_or(false, |name| &name.0 == target),
+            Self::Wildcard(pattern) => {
+                name.map_or(false, |name| wildcard_match(pattern, &name.0))
+            }
+            Self::Group(group) => groups.map_or(false, |groups| {
+                groups.0.iter().any(|candidate| candidate == group)
+            }),
+        }
+    }
+}
+
+/// Matches `name` against a pattern where `*` stands for any run of
+/// characters.
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    if !name.starts_with(first) {
+        return false;
+    }
+    let mut rest = &name[first.len()..];
+    let parts: Vec<&str> = parts.collect();
+    for (index, part) in parts.iter().enumerate() {
+        if index == parts.len() - 1 {
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(position) => rest = &rest[position + part.len()..],
+            None => return false,
+        }
+    }
+    rest.is_empty()
+}
+
+#[derive(Debug, Clone)]
+pub struct ActivationRequest {
+    pub active: bool,
+    pub pattern: ElementPattern,
+    resolved: bool,
+    warned: bool,
+}
+
+/// Every activation requested since the game started, in order. Requests are
+/// applied to matching elements as they spawn, so targets in other levels or
+/// levels that haven't loaded yet still pick them up.
+#[derive(Default)]
+pub struct ActivationRegistry {
+    requests: Vec<ActivationRequest>,
+}
+
+impl ActivationRegistry {
+    pub fn push(&mut self, active: bool, target: &str) -> usize {
+        let pattern = ElementPattern::parse(target);
+        // Only the latest request for the same target can have any effect
+        self.requests.retain(|request| request.pattern != pattern);
+        self.requests.push(ActivationRequest {
+            active,
+            pattern,
+            resolved: false,
+            warned: false,
+        });
+        self.requests.len() - 1
+    }
+
+    /// The activation state the registry wants for an element, if any
+    /// request matches it.
+    pub fn resolve(
+        &mut self,
+        name: Option<&NamedElement>,
+        groups: Option<&ElementGroups>,
+    ) -> Option<bool> {
+        let mut active = None;
+        for request in self.requests.iter_mut() {
+            if request.pattern.matches(name, groups) {
+                request.resolved = true;
+                active = Some(request.active);
+            }
+        }
+        active
+    }
+}
+
+fn set_element_active(
+    commands: &mut Commands,
+    world_state: &mut WorldState,
+    entity: Entity,
+    name: Option<&NamedElement>,
+    level: Option<&OwningLevel>,
+    active: bool,
+) {
+    if active {
+        commands.entity(entity).insert(ActiveElement);
+    } else {
+        commands.entity(entity).insert(DeactivateElement);
+    }
+    if let (Some(name), Some(level)) = (name, level) {
+        world_state.record_active(&level.0, &name.0, active);
+    }
+}
+
+fn queue_activations(
+    mut commands: Commands,
+    mut event_reader: EventReader<ActivationEvent>,
+    mut registry: ResMut<ActivationRegistry>,
+    mut world_state: ResMut<WorldState>,
+    elements: Query<
+        (
+            Entity,
+            Option<&NamedElement>,
+            Option<&ElementGroups>,
+            Option<&OwningLevel>,
+        ),
+        Or<(With<NamedElement>, With<ElementGroups>)>,
+    >,
+) {
+    for ActivationEvent(active, target) in event_reader.iter() {
+        bevy::log::info!("Activation: {} {}", active, target);
+        let index = registry.push(*active, target);
+        let request = &mut registry.requests[index];
+
+        for (entity, name, groups, level) in elements.iter() {
+            if request.pattern.matches(name, groups) {
+                bevy::log::info!("Found entity {:?}", entity);
+                request.resolved = true;
+                set_element_active(
+                    &mut commands,
+                    &mut world_state,
+                    entity,
+                    name,
+                    level,
+                    *active,
+                );
+            }
+        }
+
+        if !request.resolved {
+            bevy::log::info!(
+                "No element matches {} yet - it will be applied when one spawns",
+                target
+            );
+        }
+    }
+}
+
+fn apply_activations_to_new_elements(
+    mut commands: Commands,
+    mut registry: ResMut<ActivationRegistry>,
+    mut world_state: ResMut<WorldState>,
+    elements: Query<
+        (
+            Entity,
+            Option<&NamedElement>,
+            Option<&ElementGroups>,
+            Option<&OwningLevel>,
+        ),
+        Or<(Added<NamedElement>, Added<ElementGroups>)>,
+    >,
+) {
+    for (entity, name, groups, level) in elements.iter() {
+        if let Some(active) = registry.resolve(name, groups) {
+            set_element_active(
+                &mut commands,
+                &mut world_state,
+                entity,
+                name,
+                level,
+                active,
+            );
+        }
+    }
+}
+
+/// Once a level has loaded, warns about requests that still don't match
+/// anything, as they're likely a typo in the story or the level. Each one
+/// is only reported once, since it may yet match in a level further on.
+fn warn_unresolved_activations(mut registry: ResMut<ActivationRegistry>) {
+    for request in registry
+        .requests
+        .iter_mut()
+        .filter(|r| !r.resolved && !r.warned)
+    {
+        bevy::log::warn!(
+            "Activation of {:?} doesn't match any element in the loaded levels",
+            &request.pattern
+        );
+        request.warned = true;
+    }
+}
+
+fn reset_activation_registry(mut registry: ResMut<ActivationRegistry>) {
+    registry.requests.clear();
+}
@@ -1,11 +1,17 @@
 use std::f32::consts::PI;
 
 use crate::{
-    level::{ActiveElement, ClearLevelElement, DeactivateElement},
-    states::States,
+    interactive_narrative::SetCurrentKnotEvent,
+    level::ActiveElement,
+    music::MusicMixer,
+    physics::GameCollisionLayers,
+    spirit::TargetKnot,
+    states::{GameMode, States},
 };
 use bevy::prelude::*;
+use bevy_ecs_ldtk::{prelude::FieldValue, EntityInstance};
 use bevy_kira_audio::prelude::*;
+use heron::{prelude::*, rapier_plugin::PhysicsWorld};
 
 use super::player::PlayerControl;
 
@@ -15,108 +21,366 @@ impl Plugin for AudioPlayerPlugin {
     fn build(&self, app: &mut App) {
         app.add_plugin(AudioPlugin)
             .insert_resource(AudioSpiritVolume(0.))
-            .add_system_set(
-                SystemSet::on_update(States::InGame).with_system(play_loop),
-            )
+            .init_resource::<ConversationAudio>()
+            .init_resource::<SpeakingSpirit>()
             .add_system_set(
                 SystemSet::on_update(States::InGame)
-                    .with_system(adjust_audio_loop_position_and_volume),
-            )
-            .add_system_to_stage(CoreStage::PostUpdate, despawn_audio);
+                    .with_system(track_emitters)
+                    .with_system(track_speaking_spirit)
+                    .with_system(fade_emitters)
+                    .with_system(
+                        adjust_audio_loop_position_and_volume
+                            .after(fade_emitters),
+                    ),
+            );
     }
 }
 
-#[derive(Component)]
-struct AudioInstanceHandle(Handle<AudioInstance>);
-
+/// A spirit's music stem. The stem itself is played by the `MusicMixer`;
+/// the emitter only decides how loud it is and where it's heard from.
 #[derive(Component)]
 pub struct AudioEmitter(pub Handle<AudioSource>, pub String);
 
 const AUDIO_RANGE: f32 = 500.;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Falloff {
+    Linear,
+    /// Drops off quickly near the edge of the range.
+    Quadratic,
+    /// Stays loud for longer, then fades near the edge of the range.
+    Logarithmic,
+}
+
+impl Falloff {
+    /// Volume at `distance` through the range, from `0` to `1`.
+    pub fn volume(self, distance: f32) -> f32 {
+        let remaining = (1. - distance).clamp(0., 1.);
+        match self {
+            Self::Linear => remaining,
+            Self::Quadratic => remaining * remaining,
+            Self::Logarithmic => (1. + 9. * remaining).log10(),
+        }
+    }
+}
+
+/// How an emitter is heard from a distance, from the `AudioRange`,
+/// `AudioFalloff` and `AudioOcclusion` LDtk fields.
+#[derive(Component, Debug, Clone, Copy)]
+pub struct SpatialAudio {
+    pub range: f32,
+    pub falloff: Falloff,
+    /// How much quieter the emitter gets with a wall in the way, from `0`
+    /// (not at all) to `1` (silent). Audio instances don't expose filters, so
+    /// walls muffle by volume rather than with a low-pass.
+    pub occlusion: f32,
+}
+
+impl Default for SpatialAudio {
+    fn default() -> Self {
+        Self {
+            range: AUDIO_RANGE,
+            falloff: Falloff::Linear,
+            occlusion: 0.6,
+        }
+    }
+}
+
+impl SpatialAudio {
+    pub fn from_instance(instance: &EntityInstance) -> Self {
+        let mut spatial = Self::default();
+        for field in instance.field_instances.iter() {
+            match (field.identifier.as_str(), &field.value) {
+                ("AudioRange", FieldValue::Float(Some(range))) => {
+                    spatial.range = *range;
+                }
+                ("AudioFalloff", FieldValue::Enum(Some(falloff))) => {
+                    spatial.falloff = match falloff.as_str() {
+                        "Quadratic" => Falloff::Quadratic,
+                        "Logarithmic" => Falloff::Logarithmic,
+                        _ => Falloff::Linear,
+                    };
+                }
+                ("AudioOcclusion", FieldValue::Float(Some(occlusion))) => {
+                    spatial.occlusion = occlusion.clamp(0., 1.);
+                }
+                _ => {}
+            }
+        }
+        spatial
+    }
+}
+
+/// How occluded an emitter is, eased towards whether a wall is in the way
+/// so it doesn't cut in and out, and how far it has faded in since it was
+/// activated. Volume and pan are smoothed by the mixer.
+#[derive(Component, Default)]
+struct SpatialAudioState {
+    occluded: f32,
+    fade: f32,
+}
+
+/// How quickly occlusion catches up with walls coming and going, per
+/// second.
+const AUDIO_SMOOTHING: f32 = 8.;
+
+/// Seconds for an emitter to fade in when it's activated, and out when it's
+/// deactivated.
+const EMITTER_FADE_TIME: f32 = 1.5;
+
+/// Moves an emitter's fade towards fully in while it's active, or out while
+/// it isn't, by `step`.
+fn step_fade(fade: f32, active: bool, step: f32) -> f32 {
+    if active {
+        (fade + step).min(1.)
+    } else {
+        (fade - step).max(0.)
+    }
+}
+
+/// What spirit audio does while a conversation is open.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConversationAudio {
+    /// Keep playing as if exploring.
+    Continue,
+    /// Duck buses by the levels in the sound bank, so the conversation can
+    /// be heard, and bring the speaking spirit's stem forward.
+    Duck,
+    /// Pause the music until the conversation ends.
+    Pause,
+}
+
+impl Default for ConversationAudio {
+    fn default() -> Self {
+        Self::Duck
+    }
+}
+
+/// The spirit whose knot started the current conversation.
+#[derive(Default)]
+pub struct SpeakingSpirit(pub Option<Entity>);
+
 pub struct AudioSpiritVolume(pub f32);
 
-fn play_loop(
+/// The angle from where the listener is facing to a sound, in radians with
+/// sounds to the right being negative.
+pub fn listener_angle(listener: &Transform, position: Vec3) -> f32 {
+    let direction = (position - listener.translation).normalize_or_zero();
+    let facing = listener.rotation.mul_vec3(Vec3::Y).normalize_or_zero();
+
+    -1. * Quat::from_rotation_arc(facing, direction)
+        .to_euler(EulerRot::XYZ)
+        .2
+}
+
+fn track_emitters(
     mut commands: Commands,
-    audio: Res<Audio>,
-    emitters: Query<(Entity, &AudioEmitter), Without<AudioInstanceHandle>>,
+    mixer: Option<Res<MusicMixer>>,
+    emitters: Query<(Entity, &AudioEmitter), Without<SpatialAudioState>>,
 ) {
     for (entity, emitter) in emitters.iter() {
-        let handle = audio
-            .play(emitter.0.clone())
-            .looped()
-            .with_volume(0.)
-            .handle();
-        commands.entity(entity).insert(AudioInstanceHandle(handle));
+        let is_stem = mixer
+            .as_ref()
+            .map_or(false, |mixer| mixer.stem_for_file(&emitter.1).is_some());
+        if !is_stem {
+            bevy::log::warn!("{} isn't one of the music stems", &emitter.1);
+        }
+        commands.entity(entity).insert(SpatialAudioState::default());
     }
 }
 
-fn adjust_audio_loop_position_and_volume(
-    mut instances: ResMut<Assets<AudioInstance>>,
-    emitters: Query<
-        (&Transform, &AudioInstanceHandle, &AudioEmitter),
-        With<ActiveElement>,
-    >,
-    target: Query<&Transform, With<PlayerControl>>,
-    spirit_volume: Res<AudioSpiritVolume>,
+/// Finds the spirit a conversation belongs to from the knot it moved the
+/// story to. Knots from portals and triggers don't have one.
+fn track_speaking_spirit(
+    mut knots: EventReader<SetCurrentKnotEvent>,
+    mut speaking: ResMut<SpeakingSpirit>,
+    spirits: Query<(Entity, &TargetKnot), With<ActiveElement>>,
 ) {
-    let target = target.get_single();
-
-    if let Ok(target) = target {
-        for (emitter, handle, emitter_info) in emitters.iter() {
-            if let Some(instance) = instances.get_mut(&handle.0) {
-                let diff = emitter.translation - target.translation;
-                let volume = (AUDIO_RANGE - diff.length()) / AUDIO_RANGE;
-
-                let direction = diff.normalize_or_zero();
-                let facing =
-                    target.rotation.mul_vec3(Vec3::Y).normalize_or_zero();
-
-                let angle = -1.
-                    * Quat::from_rotation_arc(facing, direction)
-                        .to_euler(EulerRot::XYZ)
-                        .2;
-
-                let pan = (angle.sin() + 1.) / 2.;
-                let volume =
-                    volume * 0.9 + volume * 0.1 * (1. - angle.abs() / PI);
-                let volume = volume * spirit_volume.0;
-                let volume = volume.clamp(0., 1.);
-                let pan = pan.clamp(0., 1.);
-                bevy::log::debug!(
-                    "{} - Angle: {} Volume: {}, Pan: {}",
-                    emitter_info.1,
-                    angle,
-                    volume,
-                    pan
-                );
-
-                instance.set_volume(volume.into(), AudioTween::default());
-                instance.set_panning(pan.into(), AudioTween::default());
-            }
-        }
+    for SetCurrentKnotEvent(knot) in knots.iter() {
+        speaking.0 = knot.as_ref().and_then(|knot| {
+            spirits
+                .iter()
+                .find(|(_, target)| &target.0 == knot)
+                .map(|(entity, _)| entity)
+        });
     }
 }
 
-fn despawn_audio(
-    mut instances: ResMut<Assets<AudioInstance>>,
-    emitters: Query<&AudioInstanceHandle, Added<ClearLevelElement>>,
+/// Fades emitters in while they're active and out once they've been
+/// deactivated, rather than cutting their stems in and out.
+fn fade_emitters(
+    mut emitters: Query<(&mut SpatialAudioState, Option<&ActiveElement>)>,
+    time: Res<Time>,
 ) {
-    for handle in emitters.iter() {
-        if let Some(instance) = instances.get_mut(&handle.0) {
-            instance.stop(AudioTween::default());
-            instances.remove(&handle.0);
-        }
+    let step = time.delta_seconds() / EMITTER_FADE_TIME;
+    for (mut state, active) in emitters.iter_mut() {
+        state.fade = step_fade(state.fade, active.is_some(), step);
     }
 }
 
-fn deactivate_audio(
-    mut instances: ResMut<Assets<AudioInstance>>,
-    emitters: Query<&AudioInstanceHandle, Added<DeactivateElement>>,
+fn adjust_audio_loop_position_and_volume(
+    mut mixer: ResMut<MusicMixer>,
+    mut emitters: Query<(
+        Entity,
+        &Transform,
+        &AudioEmitter,
+        Option<&SpatialAudio>,
+        &mut SpatialAudioState,
+    )>,
+    target: Query<&Transform, With<PlayerControl>>,
+    physics_world: PhysicsWorld,
+    game_mode: Res<State<GameMode>>,
+    speaking: Res<SpeakingSpirit>,
+    time: Res<Time>,
 ) {
-    for handle in emitters.iter() {
-        if let Some(instance) = instances.get_mut(&handle.0) {
-            instance.set_volume(0., AudioTween::default());
+    let target = match target.get_single() {
+        Ok(target) => target,
+        Err(_) => return,
+    };
+    let smoothing = 1. - (-AUDIO_SMOOTHING * time.delta_seconds()).exp();
+    let speaking = match game_mode.current() {
+        GameMode::Conversation => speaking.0,
+        _ => None,
+    };
+
+    for (entity, emitter, emitter_info, spatial, mut state) in
+        emitters.iter_mut()
+    {
+        if state.fade <= 0. {
+            continue;
+        }
+
+        let spatial = spatial.copied().unwrap_or_default();
+        let diff = emitter.translation - target.translation;
+        let distance = diff.truncate().length();
+        let volume = spatial.falloff.volume(distance / spatial.range);
+
+        // Walls between the player and the emitter muffle it. Only worth
+        // checking while it can be heard at all.
+        let occluded = volume > 0.
+            && physics_world
+                .ray_cast_with_filter(
+                    target.translation,
+                    diff,
+                    true,
+                    CollisionLayers::all_groups::<GameCollisionLayers>()
+                        .with_masks([GameCollisionLayers::World]),
+                    |hit| hit != entity,
+                )
+                .map_or(false, |hit| {
+                    (hit.collision_point - target.translation).length()
+                        < distance
+                });
+
+        let angle = listener_angle(target, emitter.translation);
+        let pan = (angle.sin() + 1.) / 2.;
+        let volume = volume * 0.9 + volume * 0.1 * (1. - angle.abs() / PI);
+
+        state.occluded +=
+            (if occluded { 1. } else { 0. } - state.occluded) * smoothing;
+        let volume = volume * (1. - spatial.occlusion * state.occluded);
+        let volume = (volume * state.fade).clamp(0., 1.);
+        let pan = pan.clamp(0., 1.);
+        bevy::log::debug!(
+            "{} - Angle: {} Volume: {}, Pan: {}, Occluded: {}",
+            emitter_info.1,
+            angle,
+            volume,
+            pan,
+            state.occluded
+        );
+
+        mixer.request(&emitter_info.1, volume, pan);
+        if speaking == Some(entity) {
+            mixer.focus(&emitter_info.1);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    /// A world whose clock has just moved on by `seconds`.
+    fn world_after(seconds: f32) -> World {
+        let mut time = Time::default();
+        let start = Instant::now();
+        time.update_with_instant(start);
+        time.update_with_instant(start + Duration::from_secs_f32(seconds));
+        let mut world = World::default();
+        world.insert_resource(time);
+        world
+    }
+
+    fn fade(world: &World, entity: Entity) -> f32 {
+        world.get::<SpatialAudioState>(entity).unwrap().fade
+    }
+
+    #[test]
+    fn active_emitters_fade_in() {
+        let step = 0.5 / EMITTER_FADE_TIME;
+        let fade = step_fade(0., true, step);
+        assert!(fade > 0. && fade < 1.);
+        let fade = step_fade(fade, true, step);
+        let fade = step_fade(fade, true, step);
+        assert_eq!(fade, 1.);
+        assert_eq!(step_fade(fade, true, step), 1.);
+    }
+
+    #[test]
+    fn inactive_emitters_fade_out() {
+        let step = 0.5 / EMITTER_FADE_TIME;
+        let fade = step_fade(1., false, step);
+        assert!(fade > 0. && fade < 1.);
+        let fade = step_fade(fade, false, step);
+        let fade = step_fade(fade, false, step);
+        assert_eq!(fade, 0.);
+        assert_eq!(step_fade(fade, false, step), 0.);
+    }
+
+    #[test]
+    fn deactivated_emitters_fade_out_over_time() {
+        let mut world = world_after(EMITTER_FADE_TIME / 2.);
+        let emitter = world
+            .spawn()
+            .insert(SpatialAudioState {
+                occluded: 0.,
+                fade: 1.,
+            })
+            .insert(ActiveElement)
+            .id();
+        let mut stage = SystemStage::single(fade_emitters);
+        stage.run(&mut world);
+        assert_eq!(fade(&world, emitter), 1.);
+
+        // Deactivating a spirit takes its `ActiveElement` away
+        world.entity_mut(emitter).remove::<ActiveElement>();
+        stage.run(&mut world);
+        assert!((fade(&world, emitter) - 0.5).abs() < 1e-4);
+        stage.run(&mut world);
+        assert_eq!(fade(&world, emitter), 0.);
+    }
+
+    #[test]
+    fn activated_emitters_fade_in_over_time() {
+        let mut world = world_after(EMITTER_FADE_TIME / 2.);
+        let emitter = world
+            .spawn()
+            .insert(SpatialAudioState::default())
+            .insert(ActiveElement)
+            .id();
+        let mut stage = SystemStage::single(fade_emitters);
+        stage.run(&mut world);
+        assert!((fade(&world, emitter) - 0.5).abs() < 1e-4);
+        stage.run(&mut world);
+        assert_eq!(fade(&world, emitter), 1.);
+    }
+
+    #[test]
+    fn fading_turns_around_midway() {
+        let fade = step_fade(0.5, false, 0.1);
+        assert!(step_fade(fade, true, 0.1) > fade);
+    }
+}
@@ -1,81 +1,454 @@
 use bevy::prelude::*;
+use bevy_ecs_ldtk::{ldtk::Level, prelude::*};
+use heron::prelude::*;
 
-use crate::{player::PlayerControl, states::States};
+use crate::{
+    level::{selected_level, LevelBounds, LevelLoadMode, NamedElement},
+    loading_state::LoadedAssets,
+    noise::xorshift,
+    pixel_perfect::PixelPerfect,
+    player::PlayerControl,
+    states::{GameMode, States},
+};
 
 pub struct CameraPlugin;
 
 impl Plugin for CameraPlugin {
     fn build(&self, app: &mut App) {
-        app.add_startup_system(load_camera).add_system_set(
-            SystemSet::on_update(States::InGame)
-                .with_system(follow_user)
-                .with_system(set_user_position),
-        );
+        app.add_event::<CameraCommandEvent>()
+            .add_startup_system(load_camera)
+            .add_system_set(
+                SystemSet::on_update(States::InGame)
+                    .with_system(snap_to_user)
+                    .with_system(command_camera)
+                    .with_system(
+                        follow_user.after(snap_to_user).after(command_camera),
+                    ),
+            )
+            .add_system_set(
+                SystemSet::on_exit(GameMode::Conversation)
+                    .with_system(reset_camera),
+            );
+    }
+}
+
+/// Projection scale with no level zoom, when drawing straight to the
+/// window. A pixel perfect render target is already sized for the view, so
+/// draws at a scale of `1`.
+const CAMERA_SCALE: f32 = 0.4;
+
+fn base_scale(pixel_perfect: &PixelPerfect) -> f32 {
+    if pixel_perfect.enabled {
+        1.
+    } else {
+        CAMERA_SCALE
     }
 }
+const CAMERA_Z: f32 = 99.;
+
+/// How quickly the look-ahead and zoom ease towards where they're going,
+/// per second.
+const LOOK_SMOOTHING: f32 = 2.;
+const ZOOM_SMOOTHING: f32 = 3.;
 
+/// How long cinematic moves take to settle, in seconds.
+const CINEMATIC_SMOOTH_TIME: f32 = 0.9;
+/// Furthest a full strength shake throws the camera, in pixels.
+const SHAKE_DISTANCE: f32 = 10.;
+/// How much shake strength fades per second.
+const SHAKE_DECAY: f32 = 1.2;
+
+/// Keeps the player in view without chasing every step they take.
 #[derive(Component)]
-struct FollowCam(Vec3, f32);
+pub struct FollowCam {
+    /// Half the size of the box the player can move around in before the
+    /// camera follows.
+    pub dead_zone: Vec2,
+    /// How far ahead of the player the camera looks while they walk.
+    pub look_ahead: f32,
+    /// Roughly how long the camera takes to catch up, in seconds.
+    pub smooth_time: f32,
+    /// The point the dead zone is centred on.
+    focus: Vec2,
+    look: Vec2,
+    /// Where the camera is before any shake.
+    position: Vec2,
+    velocity: Vec2,
+}
+
+impl Default for FollowCam {
+    fn default() -> Self {
+        Self {
+            dead_zone: Vec2::new(24., 16.),
+            look_ahead: 40.,
+            smooth_time: 0.35,
+            focus: Vec2::ZERO,
+            look: Vec2::ZERO,
+            position: Vec2::ZERO,
+            velocity: Vec2::ZERO,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockAxis {
+    None,
+    /// The camera stays at the level's horizontal centre.
+    X,
+    /// The camera stays at the level's vertical centre.
+    Y,
+}
+
+/// Per-level camera settings, from the `CameraZoom` and `LockAxis` LDtk
+/// level fields.
+#[derive(Debug, Clone, Copy)]
+pub struct CameraOverrides {
+    pub zoom: f32,
+    pub lock: LockAxis,
+}
 
-fn load_camera(mut commands: Commands) {
+impl Default for CameraOverrides {
+    fn default() -> Self {
+        Self {
+            zoom: 1.,
+            lock: LockAxis::None,
+        }
+    }
+}
+
+impl CameraOverrides {
+    pub fn from_level(level: &Level) -> Self {
+        let mut overrides = Self::default();
+        for field in level.field_instances.iter() {
+            match (field.identifier.as_str(), &field.value) {
+                ("CameraZoom", FieldValue::Float(Some(zoom))) if *zoom > 0. => {
+                    overrides.zoom = *zoom;
+                }
+                ("LockAxis", FieldValue::Enum(Some(axis))) => {
+                    overrides.lock = match axis.as_str() {
+                        "X" => LockAxis::X,
+                        "Y" => LockAxis::Y,
+                        _ => LockAxis::None,
+                    };
+                }
+                _ => {}
+            }
+        }
+        overrides
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CameraCommand {
+    /// Move to the element with this `EntityId`.
+    Focus(String),
+    /// Zoom in by this much on top of the level's zoom, or out if it's
+    /// under `1`.
+    Zoom(f32),
+    /// Shake with this strength, from `0` to `1`, fading out.
+    Shake(f32),
+    /// Go back to following the player at the level's zoom.
+    Reset,
+}
+
+impl CameraCommand {
+    /// Parses the part of a `#camera:` tag after the prefix, like
+    /// `focus:portal`, `zoom:0.6`, `shake:0.5` or `reset`.
+    pub fn parse(command: &str) -> Option<Self> {
+        let mut parts = command.trim().splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some("focus"), Some(target)) => {
+                Some(Self::Focus(target.trim().to_string()))
+            }
+            (Some("zoom"), Some(zoom)) => zoom
+                .trim()
+                .parse()
+                .ok()
+                .filter(|zoom: &f32| *zoom > 0.)
+                .map(Self::Zoom),
+            (Some("shake"), Some(strength)) => {
+                strength.trim().parse().ok().map(Self::Shake)
+            }
+            (Some("reset"), None) => Some(Self::Reset),
+            _ => None,
+        }
+    }
+}
+
+pub struct CameraCommandEvent(pub CameraCommand);
+
+/// What the story has asked the camera to do, on top of following the
+/// player.
+#[derive(Component)]
+pub struct CinematicCamera {
+    pub target: Option<String>,
+    pub zoom: f32,
+    pub shake: f32,
+    seed: u32,
+}
+
+impl Default for CinematicCamera {
+    fn default() -> Self {
+        Self {
+            target: None,
+            zoom: 1.,
+            shake: 0.,
+            seed: 0x2545_F491,
+        }
+    }
+}
+
+impl CinematicCamera {
+    /// Noise in `-1..1`, for shaking.
+    fn noise(&mut self) -> f32 {
+        xorshift(&mut self.seed) * 2. - 1.
+    }
+}
+
+fn load_camera(mut commands: Commands, pixel_perfect: Res<PixelPerfect>) {
     commands
         .spawn_bundle(Camera2dBundle {
             projection: OrthographicProjection {
-                scale: 0.4,
+                scale: base_scale(&pixel_perfect),
                 ..Default::default()
             },
             ..Default::default()
         })
-        .insert(FollowCam(Vec3::ZERO, 500.));
+        .insert(FollowCam::default())
+        .insert(CinematicCamera::default());
 }
 
-fn set_user_position(
+/// Moves towards `target` like a critically damped spring, so it settles
+/// as fast as it can without overshooting.
+fn smooth_damp(
+    current: Vec2,
+    target: Vec2,
+    velocity: &mut Vec2,
+    smooth_time: f32,
+    delta: f32,
+) -> Vec2 {
+    let omega = 2. / smooth_time.max(0.001);
+    let x = omega * delta;
+    let decay = 1. / (1. + x + 0.48 * x * x + 0.235 * x * x * x);
+    let change = current - target;
+    let temp = (*velocity + omega * change) * delta;
+    *velocity = (*velocity - omega * temp) * decay;
+    target + (change + temp) * decay
+}
+
+/// Where the camera should be centred for a focus point, kept inside the
+/// level and on any locked axis. Levels smaller than the view are centred.
+fn frame(
+    goal: Vec2,
+    view: Vec2,
+    bounds: Option<LevelBounds>,
+    lock: LockAxis,
+) -> Vec2 {
+    let bounds = match bounds {
+        Some(bounds) => bounds,
+        None => return goal,
+    };
+    let center = (bounds.min + bounds.max) / 2.;
+    let min = bounds.min + view / 2.;
+    let max = bounds.max - view / 2.;
+    let fit = |goal: f32, min: f32, max: f32, center: f32| {
+        if min > max {
+            center
+        } else {
+            goal.clamp(min, max)
+        }
+    };
+    let mut framed = Vec2::new(
+        fit(goal.x, min.x, max.x, center.x),
+        fit(goal.y, min.y, max.y, center.y),
+    );
+    match lock {
+        LockAxis::X => framed.x = center.x,
+        LockAxis::Y => framed.y = center.y,
+        LockAxis::None => {}
+    }
+    framed
+}
+
+/// The bounds and camera settings of the level the player is in.
+fn current_level(
+    assets: &LoadedAssets,
+    ldtk_assets: &Assets<LdtkAsset>,
+    selection: &LevelSelection,
+    mode: LevelLoadMode,
+) -> (Option<LevelBounds>, CameraOverrides) {
+    match ldtk_assets
+        .get(&assets.test_level)
+        .and_then(|ldtk| selected_level(ldtk, selection))
+    {
+        Some(level) => (
+            Some(LevelBounds::new(level, mode)),
+            CameraOverrides::from_level(level),
+        ),
+        None => (None, CameraOverrides::default()),
+    }
+}
+
+/// Jumps straight to the player when they spawn in a new level, rather than
+/// panning across from wherever the last level left the camera.
+fn snap_to_user(
     mut camera: Query<
-        (&mut Transform, &mut FollowCam),
+        (&mut Transform, &mut FollowCam, &mut OrthographicProjection),
         (With<Camera>, Without<PlayerControl>),
     >,
     player: Query<&GlobalTransform, Added<PlayerControl>>,
+    assets: Res<LoadedAssets>,
+    ldtk_assets: Res<Assets<LdtkAsset>>,
+    selection: Res<LevelSelection>,
+    mode: Res<LevelLoadMode>,
+    pixel_perfect: Res<PixelPerfect>,
 ) {
-    let target = player.get_single();
+    let target = match player.get_single() {
+        Ok(target) => target.translation().truncate(),
+        Err(_) => return,
+    };
+    let (bounds, overrides) =
+        current_level(&assets, &ldtk_assets, &selection, *mode);
 
-    if let Ok(target) = target {
-        bevy::log::info!("Setting camera to {:?}", &target);
-        let target = target.translation();
+    for (mut transform, mut follow, mut projection) in camera.iter_mut() {
+        projection.scale = base_scale(&pixel_perfect) / overrides.zoom;
+        let view = Vec2::new(
+            projection.right - projection.left,
+            projection.top - projection.bottom,
+        ) * projection.scale;
+        follow.focus = target;
+        follow.look = Vec2::ZERO;
+        follow.velocity = Vec2::ZERO;
+        let position = frame(target, view, bounds, overrides.lock);
+        bevy::log::info!("Setting camera to {:?}", &position);
+        follow.position = position;
+        transform.translation = position.extend(CAMERA_Z);
+    }
+}
 
-        for (mut transform, mut follow) in camera.iter_mut() {
-            transform.translation = Vec3::new(target.x, target.y, 99.);
-            follow.0 = Vec3::ZERO;
+fn command_camera(
+    mut events: EventReader<CameraCommandEvent>,
+    mut camera: Query<&mut CinematicCamera>,
+    named: Query<&NamedElement>,
+) {
+    for CameraCommandEvent(command) in events.iter() {
+        bevy::log::info!("Camera command {:?}", command);
+        for mut cinematic in camera.iter_mut() {
+            match command {
+                CameraCommand::Focus(target) => {
+                    if !named.iter().any(|name| &name.0 == target) {
+                        bevy::log::warn!("No element {} to focus on", target);
+                    }
+                    cinematic.target = Some(target.clone());
+                }
+                CameraCommand::Zoom(zoom) => cinematic.zoom = *zoom,
+                CameraCommand::Shake(strength) => {
+                    cinematic.shake = cinematic.shake.max(*strength);
+                }
+                CameraCommand::Reset => {
+                    cinematic.target = None;
+                    cinematic.zoom = 1.;
+                }
+            }
         }
     }
 }
 
+/// Hands the camera back to the player once a conversation is over.
+fn reset_camera(mut camera: Query<&mut CinematicCamera>) {
+    for mut cinematic in camera.iter_mut() {
+        cinematic.target = None;
+        cinematic.zoom = 1.;
+    }
+}
+
 fn follow_user(
     mut camera: Query<
-        (&mut Transform, &mut FollowCam),
+        (
+            &mut Transform,
+            &mut FollowCam,
+            &mut CinematicCamera,
+            &mut OrthographicProjection,
+        ),
         (With<Camera>, Without<PlayerControl>),
     >,
-    player: Query<&GlobalTransform, With<PlayerControl>>,
+    player: Query<(&GlobalTransform, &Velocity), With<PlayerControl>>,
+    named: Query<(&NamedElement, &GlobalTransform)>,
+    assets: Res<LoadedAssets>,
+    ldtk_assets: Res<Assets<LdtkAsset>>,
+    selection: Res<LevelSelection>,
+    mode: Res<LevelLoadMode>,
+    pixel_perfect: Res<PixelPerfect>,
     time: Res<Time>,
 ) {
-    let delta = time.delta().as_secs_f32();
-    let target = player.get_single();
+    let delta = time.delta_seconds();
+    let (target, velocity) = match player.get_single() {
+        Ok(player) => player,
+        Err(_) => return,
+    };
+    let (bounds, overrides) =
+        current_level(&assets, &ldtk_assets, &selection, *mode);
+    let position = target.translation().truncate();
+    let facing = target
+        .compute_transform()
+        .rotation
+        .mul_vec3(Vec3::Y)
+        .truncate()
+        .normalize_or_zero();
+    let moving = velocity.linear.truncate().length() > 1.;
 
-    if let Ok(target) = target {
-        let target = target.translation();
+    for (mut transform, mut follow, mut cinematic, mut projection) in
+        camera.iter_mut()
+    {
+        let zoom_smoothing = 1. - (-ZOOM_SMOOTHING * delta).exp();
+        let scale =
+            base_scale(&pixel_perfect) / (overrides.zoom * cinematic.zoom);
+        projection.scale += (scale - projection.scale) * zoom_smoothing;
+        let view = Vec2::new(
+            projection.right - projection.left,
+            projection.top - projection.bottom,
+        ) * projection.scale;
 
-        for (mut transform, mut follow) in camera.iter_mut() {
-            let direction = target - transform.translation;
-            let direction = direction.normalize();
-            let distance = follow.1;
+        // Drag the dead zone along when the player pushes against its edge.
+        let offset = position - follow.focus;
+        let outside = offset.abs() - follow.dead_zone;
+        follow.focus += offset.signum() * outside.max(Vec2::ZERO);
 
-            follow.0 += direction * delta * distance;
+        let look_smoothing = 1. - (-LOOK_SMOOTHING * delta).exp();
+        let look = if moving {
+            facing * follow.look_ahead
+        } else {
+            Vec2::ZERO
+        };
+        follow.look += (look - follow.look) * look_smoothing;
 
-            if follow.0.length() > distance {
-                follow.0 = follow.0.normalize() * (distance - 1.);
-            }
+        // A cinematic target takes over from the player, with a slower
+        // move so it reads as a deliberate shot.
+        let shot = cinematic.target.as_ref().and_then(|target| {
+            named
+                .iter()
+                .find(|(name, _)| &name.0 == target)
+                .map(|(_, transform)| transform.translation().truncate())
+        });
+        let (goal, smooth_time) = match shot {
+            Some(shot) => (shot, CINEMATIC_SMOOTH_TIME),
+            None => (follow.focus + follow.look, follow.smooth_time),
+        };
+        let goal = frame(goal, view, bounds, overrides.lock);
+        let current = follow.position;
+        follow.position = smooth_damp(
+            current,
+            goal,
+            &mut follow.velocity,
+            smooth_time,
+            delta,
+        );
 
-            transform.translation += follow.0 * delta;
-            transform.translation.z = 99.;
-        }
+        let strength = cinematic.shake * cinematic.shake;
+        let shake = Vec2::new(cinematic.noise(), cinematic.noise())
+            * SHAKE_DISTANCE
+            * strength;
+        cinematic.shake = (cinematic.shake - SHAKE_DECAY * delta).max(0.);
+        transform.translation = (follow.position + shake).extend(CAMERA_Z);
     }
 }
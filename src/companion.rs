@@ -0,0 +1,278 @@
+use bevy::prelude::*;
+use heron::{prelude::*, rapier_plugin::PhysicsWorld};
+
+use crate::{
+    level::{LevelElement, NamedElement},
+    physics::GameCollisionLayers,
+    player::PlayerControl,
+    spirit::{CharacterAtlas, FailedInteractEvent, SpiritProximity},
+    sprite_animation::{Facing, SpriteAnimator},
+    states::{GameMode, States},
+    steering::{avoid_walls, wall_clearance},
+};
+
+pub struct CompanionPlugin;
+
+impl Plugin for CompanionPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<CompanionCommandEvent>()
+            .add_system_set(
+                SystemSet::on_update(States::InGame)
+                    .with_system(spawn_companion)
+                    .with_system(command_companion)
+                    .with_system(follow_player)
+                    .with_system(animate_companion),
+            )
+            .add_system_set(
+                SystemSet::on_exit(GameMode::Conversation)
+                    .with_system(resume_following),
+            );
+    }
+}
+
+/// Cass's sprite, which bobs around above the `CompanionFollower` body.
+#[derive(Component)]
+pub struct Companion;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompanionCommand {
+    Follow,
+    Stay,
+    /// Walk up to the element with this `EntityId`.
+    Approach(String),
+}
+
+impl CompanionCommand {
+    /// Parses the part of a `#cass:` tag after the prefix, like `follow`,
+    /// `stay` or `approach:pontersons_essence`.
+    pub fn parse(command: &str) -> Option<Self> {
+        let mut parts = command.trim().splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some("follow"), None) => Some(Self::Follow),
+            (Some("stay"), None) => Some(Self::Stay),
+            (Some("approach"), Some(target)) => {
+                Some(Self::Approach(target.trim().to_string()))
+            }
+            _ => None,
+        }
+    }
+}
+
+pub struct CompanionCommandEvent(pub CompanionCommand);
+
+#[derive(Component)]
+pub struct CompanionFollower {
+    pub command: CompanionCommand,
+    pub max_speed: f32,
+    pub acceleration: f32,
+}
+
+/// Where Cass waits relative to the player, in the player's frame.
+const FOLLOW_OFFSET: Vec3 = Vec3::new(-24., -28., 0.);
+/// How close Cass has to be to where she's heading to stop and idle.
+const ARRIVE_RADIUS: f32 = 12.;
+/// Distance over which Cass slows down as she arrives.
+const SLOWING_RADIUS: f32 = 80.;
+/// How far from an element Cass stops when approaching it.
+const APPROACH_DISTANCE: f32 = 40.;
+/// Spirits closer than this catch Cass's attention while she idles.
+const NOTICE_RANGE: f32 = 300.;
+
+fn spawn_companion(
+    mut commands: Commands,
+    players: Query<&Transform, Added<PlayerControl>>,
+    atlas: Option<Res<CharacterAtlas>>,
+) {
+    let atlas = match atlas {
+        Some(atlas) => atlas.atlas.clone(),
+        None => return,
+    };
+    for player in players.iter() {
+        let position = player.translation + player.rotation * FOLLOW_OFFSET;
+        commands
+            .spawn_bundle(SpatialBundle::from_transform(
+                Transform::from_translation(position),
+            ))
+            .insert(LevelElement)
+            .insert(CompanionFollower {
+                command: CompanionCommand::Follow,
+                max_speed: 110.,
+                acceleration: 400.,
+            })
+            .insert(RigidBody::Dynamic)
+            .insert(RotationConstraints::lock())
+            .insert(CollisionShape::Sphere { radius: 8. })
+            .insert(Velocity::from_linear(Vec3::ZERO))
+            .insert(
+                CollisionLayers::none()
+                    .with_group(GameCollisionLayers::Companion)
+                    .with_mask(GameCollisionLayers::World),
+            )
+            .with_children(|parent| {
+                parent
+                    .spawn_bundle(SpriteSheetBundle {
+                        texture_atlas: atlas.clone(),
+                        transform: Transform::from_translation(Vec3::Z)
+                            .with_scale(Vec3::ONE * 0.4),
+                        ..default()
+                    })
+                    .insert(SpriteAnimator::new("cass", "idle"))
+                    .insert(Companion);
+            });
+    }
+}
+
+fn command_companion(
+    mut events: EventReader<CompanionCommandEvent>,
+    mut companions: Query<&mut CompanionFollower>,
+) {
+    for CompanionCommandEvent(command) in events.iter() {
+        bevy::log::info!("Cass: {:?}", command);
+        for mut companion in companions.iter_mut() {
+            companion.command = command.clone();
+        }
+    }
+}
+
+fn resume_following(mut companions: Query<&mut CompanionFollower>) {
+    for mut companion in companions.iter_mut() {
+        companion.command = CompanionCommand::Follow;
+    }
+}
+
+fn follow_player(
+    mut companions: Query<
+        (&Transform, &CompanionFollower, &mut Velocity, &Children),
+        Without<PlayerControl>,
+    >,
+    players: Query<(&Transform, &Velocity), With<PlayerControl>>,
+    elements: Query<(&GlobalTransform, &NamedElement)>,
+    mut sprites: Query<&mut SpriteAnimator, With<Companion>>,
+    proximity: Res<SpiritProximity>,
+    spirits: Query<&GlobalTransform>,
+    time: Res<Time>,
+    physics_world: PhysicsWorld,
+) {
+    let (player, player_velocity) = match players.get_single() {
+        Ok(player) => player,
+        Err(_) => return,
+    };
+
+    for (transform, companion, mut velocity, children) in
+        companions.iter_mut()
+    {
+        let position = transform.translation;
+        let target = match &companion.command {
+            CompanionCommand::Follow => {
+                Some(player.translation + player.rotation * FOLLOW_OFFSET)
+            }
+            CompanionCommand::Stay => None,
+            CompanionCommand::Approach(name) => elements
+                .iter()
+                .find(|(_, element)| &element.0 == name)
+                .map(|(element, _)| {
+                    let element = element.translation();
+                    let towards_player =
+                        (player.translation - element).normalize_or_zero();
+                    element + towards_player * APPROACH_DISTANCE
+                }),
+        };
+
+        let offset = target
+            .map(|target| (target - position).truncate().extend(0.))
+            .unwrap_or_default();
+        let distance = offset.length();
+        let arrived = distance < ARRIVE_RADIUS;
+
+        let desired = if arrived {
+            Vec3::ZERO
+        } else {
+            let clearance = |direction: Vec3| {
+                wall_clearance(&physics_world, position, direction)
+            };
+            let direction = avoid_walls(offset / distance, &clearance);
+            // Slow down on arrival, but keep up with a running player
+            let speed = (companion.max_speed * distance / SLOWING_RADIUS)
+                .min(companion.max_speed)
+                .max(player_velocity.linear.length().min(companion.max_speed));
+            direction * speed
+        };
+
+        let force = (desired - velocity.linear)
+            .clamp_length_max(companion.acceleration * time.delta_seconds());
+        velocity.linear += force;
+
+        // Face where she's going, or the nearest spirit, or the player
+        let moving = velocity.linear.length() > 5.;
+        let look = if moving {
+            velocity.linear
+        } else {
+            proximity
+                .nearest
+                .and_then(|spirit| spirits.get(spirit).ok())
+                .map(|spirit| spirit.translation())
+                .filter(|spirit| {
+                    (*spirit - position).truncate().length() < NOTICE_RANGE
+                })
+                .unwrap_or(player.translation)
+                - position
+        };
+
+        for child in children.iter() {
+            if let Ok(mut animator) = sprites.get_mut(*child) {
+                if look.truncate().length() > 0. {
+                    animator.facing = Some(Facing(look.y.atan2(look.x)));
+                }
+                animator.play(if moving { "walk" } else { "idle" });
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct CompanionMood {
+    phase: f32,
+    /// Seconds left of the reaction to an interact with nothing in reach.
+    confused: f32,
+}
+
+const CONFUSED_TIME: f32 = 0.6;
+
+fn animate_companion(
+    mut companions: Query<
+        (&mut TextureAtlasSprite, &mut SpriteAnimator, &mut Transform),
+        With<Companion>,
+    >,
+    proximity: Res<SpiritProximity>,
+    mut failed: EventReader<FailedInteractEvent>,
+    mut mood: Local<CompanionMood>,
+    time: Res<Time>,
+) {
+    if failed.iter().count() > 0 {
+        mood.confused = CONFUSED_TIME;
+    }
+    let delta = time.delta_seconds();
+    mood.confused = (mood.confused - delta).max(0.);
+    // Cass gets more excited the closer a hidden spirit is
+    let closeness = proximity.closeness;
+    mood.phase += delta * 5. * (1. + closeness * 2.);
+
+    for (mut sprite, mut animator, mut transform) in companions.iter_mut() {
+        animator.speed = 1. + closeness * 2.;
+        let position_horizontal = (mood.phase.floor() / 3.).sin() * 3.;
+        let position_vertical = (mood.phase.floor() / 4.).cos() * 1.5;
+        let shake = if mood.confused > 0. {
+            (mood.confused * 60.).sin() * mood.confused / CONFUSED_TIME
+        } else {
+            0.
+        };
+
+        sprite.color = if mood.confused > 0. {
+            Color::rgb(0.7, 0.8, 1.)
+        } else {
+            Color::rgb(1., 1. - closeness * 0.3, 1. - closeness * 0.5)
+        };
+        transform.translation =
+            Vec3::new(position_horizontal + shake, position_vertical, 1.);
+    }
+}
@@ -0,0 +1,477 @@
+use std::sync::Mutex;
+
+use inkling::Variable;
+
+use super::ink_story::InkStory;
+
+/// A boolean expression over ink variables, such as
+/// `knows_its_a_lab && !bricksworth_kicked_out` or `times_visited >= 2`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    Value(Value),
+    Not(Box<Condition>),
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>),
+    Compare(Value, Comparison, Value),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Variable(String),
+    Bool(bool),
+    Number(f32),
+    Text(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Identifier(String),
+    Number(f32),
+    Text(String),
+    Not,
+    And,
+    Or,
+    Open,
+    Close,
+    Compare(Comparison),
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut chars = source.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        if c.is_alphabetic() || c == '_' {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_alphanumeric() || c == '_' || c == '.' {
+                    word.push(c);
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            tokens.push(match word.as_str() {
+                "not" => Token::Not,
+                "and" => Token::And,
+                "or" => Token::Or,
+                _ => Token::Identifier(word),
+            });
+            continue;
+        }
+        // A minus is only a sign where a value is expected, and only right
+        // before a digit, as conditions don't do arithmetic
+        let expects_value = matches!(
+            tokens.last(),
+            None | Some(
+                Token::Not
+                    | Token::And
+                    | Token::Or
+                    | Token::Open
+                    | Token::Compare(_)
+            )
+        );
+        let mut ahead = chars.clone();
+        ahead.next();
+        let negative = c == '-'
+            && expects_value
+            && ahead.peek().map_or(false, |c| c.is_ascii_digit());
+        if c.is_ascii_digit() || negative {
+            let mut number = String::new();
+            if negative {
+                number.push(c);
+                chars.next();
+            }
+            while let Some(&c) = chars.peek() {
+                if c.is_ascii_digit() || c == '.' {
+                    number.push(c);
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            let number = number
+                .parse::<f32>()
+                .map_err(|_| format!("Invalid number {}", number))?;
+            tokens.push(Token::Number(number));
+            continue;
+        }
+
+        chars.next();
+        let next = chars.peek().copied();
+        let token = match (c, next) {
+            ('"', _) => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some(c) => text.push(c),
+                        None => return Err("Unterminated string".to_string()),
+                    }
+                }
+                Token::Text(text)
+            }
+            ('(', _) => Token::Open,
+            (')', _) => Token::Close,
+            ('&', Some('&')) => {
+                chars.next();
+                Token::And
+            }
+            ('|', Some('|')) => {
+                chars.next();
+                Token::Or
+            }
+            ('=', Some('=')) => {
+                chars.next();
+                Token::Compare(Comparison::Equal)
+            }
+            ('!', Some('=')) => {
+                chars.next();
+                Token::Compare(Comparison::NotEqual)
+            }
+            ('<', Some('=')) => {
+                chars.next();
+                Token::Compare(Comparison::LessOrEqual)
+            }
+            ('>', Some('=')) => {
+                chars.next();
+                Token::Compare(Comparison::GreaterOrEqual)
+            }
+            ('!', _) => Token::Not,
+            ('<', _) => Token::Compare(Comparison::Less),
+            ('>', _) => Token::Compare(Comparison::Greater),
+            _ => return Err(format!("Unexpected character {}", c)),
+        };
+        tokens.push(token);
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn or(&mut self) -> Result<Condition, String> {
+        let mut condition = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            condition =
+                Condition::Or(Box::new(condition), Box::new(self.and()?));
+        }
+        Ok(condition)
+    }
+
+    fn and(&mut self) -> Result<Condition, String> {
+        let mut condition = self.unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            condition =
+                Condition::And(Box::new(condition), Box::new(self.unary()?));
+        }
+        Ok(condition)
+    }
+
+    fn unary(&mut self) -> Result<Condition, String> {
+        if self.peek() == Some(&Token::Not) {
+            self.next();
+            return Ok(Condition::Not(Box::new(self.unary()?)));
+        }
+        if self.peek() == Some(&Token::Open) {
+            self.next();
+            let condition = self.or()?;
+            return match self.next() {
+                Some(Token::Close) => Ok(condition),
+                _ => Err("Missing closing bracket".to_string()),
+            };
+        }
+
+        let left = self.value()?;
+        if let Some(Token::Compare(comparison)) = self.peek().cloned() {
+            self.next();
+            let right = self.value()?;
+            return Ok(Condition::Compare(left, comparison, right));
+        }
+        Ok(Condition::Value(left))
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        match self.next() {
+            Some(Token::Identifier(name)) => Ok(match name.as_str() {
+                "true" => Value::Bool(true),
+                "false" => Value::Bool(false),
+                _ => Value::Variable(name),
+            }),
+            Some(Token::Number(number)) => Ok(Value::Number(number)),
+            Some(Token::Text(text)) => Ok(Value::Text(text)),
+            token => Err(format!("Expected a value, found {:?}", token)),
+        }
+    }
+}
+
+impl Condition {
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let mut parser = Parser {
+            tokens: tokenize(source)?,
+            position: 0,
+        };
+        let condition = parser.or()?;
+        match parser.peek() {
+            None => Ok(condition),
+            Some(token) => Err(format!("Unexpected {:?}", token)),
+        }
+    }
+
+    /// Evaluates the condition, treating unknown variables as false and
+    /// warning about them.
+    pub fn evaluate(&self, story: &InkStory) -> bool {
+        self.evaluate_with(&|name| match story.get_variable(name) {
+            Ok(variable) => Some(variable),
+            Err(err) => {
+                warn_unknown_variable(name, &err);
+                None
+            }
+        })
+    }
+
+    fn evaluate_with(&self, lookup: &dyn Fn(&str) -> Option<Variable>) -> bool {
+        match self {
+            Self::Value(value) => value.resolve(lookup).is_truthy(),
+            Self::Not(condition) => !condition.evaluate_with(lookup),
+            Self::And(left, right) => {
+                left.evaluate_with(lookup) && right.evaluate_with(lookup)
+            }
+            Self::Or(left, right) => {
+                left.evaluate_with(lookup) || right.evaluate_with(lookup)
+            }
+            Self::Compare(left, comparison, right) => {
+                let left = left.resolve(lookup);
+                let right = right.resolve(lookup);
+                match (left.as_number(), right.as_number()) {
+                    (Some(left), Some(right)) => match comparison {
+                        Comparison::Equal => left == right,
+                        Comparison::NotEqual => left != right,
+                        Comparison::Less => left < right,
+                        Comparison::LessOrEqual => left <= right,
+                        Comparison::Greater => left > right,
+                        Comparison::GreaterOrEqual => left >= right,
+                    },
+                    _ => match comparison {
+                        Comparison::Equal => left == right,
+                        Comparison::NotEqual => left != right,
+                        _ => false,
+                    },
+                }
+            }
+        }
+    }
+}
+
+/// Names of variables already warned about, as conditions are checked
+/// every frame in places.
+static UNKNOWN_VARIABLES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+fn warn_unknown_variable(name: &str, err: &dyn std::fmt::Debug) {
+    let mut unknown = match UNKNOWN_VARIABLES.lock() {
+        Ok(unknown) => unknown,
+        Err(_) => return,
+    };
+    if !unknown.iter().any(|known| known == name) {
+        bevy::log::warn!(
+            "Unknown ink variable {} in condition: {:?}",
+            name,
+            err
+        );
+        unknown.push(name.to_string());
+    }
+}
+
+impl Value {
+    fn resolve(&self, lookup: &dyn Fn(&str) -> Option<Variable>) -> Value {
+        match self {
+            Self::Variable(name) => match lookup(name) {
+                Some(Variable::Bool(value)) => Self::Bool(value),
+                Some(Variable::Int(value)) => Self::Number(value as f32),
+                Some(Variable::Float(value)) => Self::Number(value),
+                Some(Variable::String(value)) => Self::Text(value),
+                _ => Self::Bool(false),
+            },
+            value => value.clone(),
+        }
+    }
+
+    fn is_truthy(&self) -> bool {
+        match self {
+            Self::Bool(value) => *value,
+            Self::Number(value) => *value != 0.,
+            Self::Text(value) => !value.is_empty(),
+            Self::Variable(_) => false,
+        }
+    }
+
+    fn as_number(&self) -> Option<f32> {
+        match self {
+            Self::Bool(value) => Some(if *value { 1. } else { 0. }),
+            Self::Number(value) => Some(*value),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variable(name: &str) -> Value {
+        Value::Variable(name.to_string())
+    }
+
+    fn lookup(name: &str) -> Option<Variable> {
+        match name {
+            "knows_its_a_lab" => Some(Variable::Bool(true)),
+            "kicked_out" => Some(Variable::Bool(false)),
+            "times_visited" => Some(Variable::Int(2)),
+            "mood" => Some(Variable::Float(-0.5)),
+            "name" => Some(Variable::String("Cass".to_string())),
+            _ => None,
+        }
+    }
+
+    fn check(source: &str) -> bool {
+        Condition::parse(source)
+            .unwrap_or_else(|err| panic!("{}: {}", source, err))
+            .evaluate_with(&lookup)
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let a = Box::new(Condition::Value(variable("a")));
+        let b = Box::new(Condition::Value(variable("b")));
+        let c = Box::new(Condition::Value(variable("c")));
+        assert_eq!(
+            Condition::parse("a || b && c"),
+            Ok(Condition::Or(a, Box::new(Condition::And(b, c))))
+        );
+        assert!(check("knows_its_a_lab || kicked_out && kicked_out"));
+        assert!(!check("(knows_its_a_lab || kicked_out) && kicked_out"));
+    }
+
+    #[test]
+    fn words_work_like_symbols() {
+        assert_eq!(
+            Condition::parse("not a and b or c"),
+            Condition::parse("!a && b || c")
+        );
+    }
+
+    #[test]
+    fn not_negates() {
+        assert!(check("!kicked_out"));
+        assert!(!check("!knows_its_a_lab"));
+        assert!(check("!!knows_its_a_lab"));
+        assert!(check("knows_its_a_lab && !kicked_out"));
+    }
+
+    #[test]
+    fn comparisons() {
+        assert!(check("times_visited == 2"));
+        assert!(check("times_visited != 3"));
+        assert!(check("times_visited < 3"));
+        assert!(!check("times_visited < 2"));
+        assert!(check("times_visited <= 2"));
+        assert!(check("times_visited > 1"));
+        assert!(!check("times_visited > 2"));
+        assert!(check("times_visited >= 2"));
+        assert!(check("mood < 0"));
+    }
+
+    #[test]
+    fn literals() {
+        assert!(check("true"));
+        assert!(!check("false"));
+        assert!(check("knows_its_a_lab == true"));
+        assert!(check("kicked_out == false"));
+        assert!(check("name == \"Cass\""));
+        assert!(check("name != \"Ponterson\""));
+        assert!(!check("name < \"Ponterson\""));
+        assert!(check("\"\" == \"\""));
+    }
+
+    #[test]
+    fn negative_numbers() {
+        assert_eq!(
+            Condition::parse("mood > -1"),
+            Ok(Condition::Compare(
+                variable("mood"),
+                Comparison::Greater,
+                Value::Number(-1.)
+            ))
+        );
+        assert!(check("mood == -0.5"));
+        assert!(check("-1 < times_visited"));
+        assert!(check("(-1 < mood)"));
+    }
+
+    #[test]
+    fn minus_is_not_subtraction() {
+        assert!(Condition::parse("times_visited -1").is_err());
+        assert!(Condition::parse("times_visited - 1").is_err());
+        assert!(Condition::parse("times_visited > - 1").is_err());
+        assert!(Condition::parse("1-1").is_err());
+    }
+
+    #[test]
+    fn unknown_variables_are_false() {
+        assert!(!check("misspelled"));
+        assert!(check("!misspelled"));
+        assert!(!check("misspelled > 0"));
+    }
+
+    #[test]
+    fn malformed_input() {
+        for source in [
+            "",
+            "(a",
+            "a)",
+            "a &&",
+            "|| a",
+            "a & b",
+            "a | b",
+            "a ==",
+            "== a",
+            "a b",
+            "\"unterminated",
+            "a = 1",
+            "1.2.3",
+            "a @ b",
+        ] {
+            assert!(Condition::parse(source).is_err(), "{}", source);
+        }
+    }
+}
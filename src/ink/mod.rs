@@ -2,6 +2,7 @@ use bevy::prelude::*;
 
 use self::{ink_asset::*, ink_story::StoryEvent};
 
+pub mod condition;
 pub mod ink_asset;
 pub mod ink_story;
 
@@ -3,14 +3,19 @@ use inkling::{InklingError, error::variable::VariableError};
 
 use crate::{
     audio::AudioSpiritVolume,
+    camera::{CameraCommand, CameraCommandEvent},
+    companion::{CompanionCommand, CompanionCommandEvent},
+    music::director::MusicCueEvent,
     ink::{
         ink_asset::InkAsset,
         ink_story::{InkStory, StoryEvent},
     },
     level::ActivationEvent,
     loading_state::LoadedAssets,
+    sfx::PlaySoundEvent,
     states::{GameMode, States},
     theme::*,
+    voice::{line_id, speaker_key, SpeakLinesEvent, VoiceGated, VoicePlayback},
 };
 
 pub struct InteractiveNarrativePlugin;
@@ -100,6 +105,18 @@ fn clear_narrative_root(
 #[derive(Default)]
 struct CurrentCharacter(Option<(String, usize)>);
 
+/// The display name and portrait index for a character's ink tag.
+pub fn character_for_tag(tag: &str) -> Option<(&'static str, usize)> {
+    match tag {
+        "cass" => Some(("Cass", 0)),
+        "alverniss" => Some(("Mx. Alverniss", 48)),
+        "rollins" => Some(("Cpl. Rollins", 64)),
+        "bricksworth" => Some(("Mr. Bricksworth", 16)),
+        "ponterson" => Some(("Dr. Ponterson", 32)),
+        _ => None,
+    }
+}
+
 fn display_current_narrative(
     mut commands: Commands,
     mut events: EventReader<StoryEvent>,
@@ -109,6 +126,11 @@ fn display_current_narrative(
     mut game_mode: ResMut<State<GameMode>>,
     mut state: ResMut<State<States>>,
     mut activation_event: EventWriter<ActivationEvent>,
+    mut companion_event: EventWriter<CompanionCommandEvent>,
+    mut music_event: EventWriter<MusicCueEvent>,
+    mut sound_event: EventWriter<PlaySoundEvent>,
+    mut voice_event: EventWriter<SpeakLinesEvent>,
+    mut camera_event: EventWriter<CameraCommandEvent>,
     mut story: ResMut<InkStory>,
     mut character: ResMut<CurrentCharacter>
 ) {
@@ -120,6 +142,7 @@ fn display_current_narrative(
         }
 
         let mut trigger_play = false;
+        let mut voice_lines = vec![];
 
         commands
             .spawn_bundle(NodeBundle {
@@ -146,6 +169,7 @@ fn display_current_narrative(
             .insert(NarrativeDisplayRoot)
             .with_children(|parent| {
                 for line in event.lines.iter() {
+                    let mut voice = None;
                     for tag in line.tags.iter() {
                         bevy::log::info!("Processing tag {}", &tag);
                         match tag.as_str() {
@@ -158,20 +182,9 @@ fn display_current_narrative(
                                     game_mode.set(GameMode::Exploration);
                                 }
                             }
-                            "cass" => {
-                                character.0 = Some(("Cass".to_string(), 0));
-                            }
-                            "alverniss" => {
-                                character.0 = Some(("Mx. Alverniss".to_string(), 48));
-                            }
-                            "rollins" => {
-                                character.0 = Some(("Cpl. Rollins".to_string(), 64));
-                            }
-                            "bricksworth" => {
-                                character.0 = Some(("Mr. Bricksworth".to_string(), 16));
-                            }
-                            "ponterson" => {
-                                character.0 = Some(("Dr. Ponterson".to_string(), 32));
+                            tag if character_for_tag(tag).is_some() => {
+                                character.0 = character_for_tag(tag)
+                                    .map(|(name, index)| (name.to_string(), index));
                             }
                             _ => {
                                 if tag.starts_with("activate:") {
@@ -182,6 +195,46 @@ fn display_current_narrative(
                                     let target = tag.replace("deactivate:", "");
                                     activation_event
                                         .send(ActivationEvent(false, target));
+                                } else if let Some(preset) =
+                                    tag.strip_prefix("music:")
+                                {
+                                    music_event.send(MusicCueEvent(
+                                        match preset.trim() {
+                                            "auto" => None,
+                                            preset => Some(preset.to_string()),
+                                        },
+                                    ));
+                                } else if let Some(id) =
+                                    tag.strip_prefix("vo:")
+                                {
+                                    voice = Some(id.trim().to_string());
+                                } else if let Some(sound) =
+                                    tag.strip_prefix("sfx:")
+                                {
+                                    sound_event
+                                        .send(PlaySoundEvent::new(sound.trim()));
+                                } else if let Some(command) =
+                                    tag.strip_prefix("camera:")
+                                {
+                                    match CameraCommand::parse(command) {
+                                        Some(command) => camera_event
+                                            .send(CameraCommandEvent(command)),
+                                        None => bevy::log::warn!(
+                                            "Unknown camera command {}",
+                                            command
+                                        ),
+                                    }
+                                } else if let Some(command) =
+                                    tag.strip_prefix("cass:")
+                                {
+                                    match CompanionCommand::parse(command) {
+                                        Some(command) => companion_event
+                                            .send(CompanionCommandEvent(command)),
+                                        None => bevy::log::warn!(
+                                            "Unknown Cass command {}",
+                                            command
+                                        ),
+                                    }
                                 }
                             }
                         }
@@ -224,7 +277,14 @@ fn display_current_narrative(
                                 font_size: 26.0,
                                 color: TEXT_COLOR,
                             },
-                        ));
+                        ))
+                        .insert(VoiceGated(voice_lines.len()));
+                        voice_lines.push(voice.unwrap_or_else(|| {
+                            let speaker = speaker_key(
+                                character.0.as_ref().map(|(name, _)| name.as_str()),
+                            );
+                            line_id(&speaker, &line.text)
+                        }));
                     }
                 }
                 if !trigger_play {
@@ -275,6 +335,7 @@ fn display_current_narrative(
                                         .insert(NarrativeChoiceButton {
                                             choice: index.to_owned(),
                                         })
+                                        .insert(VoiceGated(voice_lines.len()))
                                         .with_children(|parent| {
                                             parent.spawn_bundle(
                                                 TextBundle::from_section(
@@ -297,6 +358,8 @@ fn display_current_narrative(
                     }
                 }
             });
+
+        voice_event.send(SpeakLinesEvent(voice_lines));
     }
 }
 
@@ -311,8 +374,13 @@ fn button_system(
         (Changed<Interaction>, With<Button>),
     >,
     mut event_writer: EventWriter<StoryEvent>,
+    mut sound_event: EventWriter<PlaySoundEvent>,
+    voice: Res<VoicePlayback>,
     story: Option<ResMut<InkStory>>,
 ) {
+    if voice.is_waiting() {
+        return;
+    }
     if let Some(mut story) = story {
         for (interaction, mut color, _children, choice) in
             &mut interaction_query
@@ -320,6 +388,7 @@ fn button_system(
             match *interaction {
                 Interaction::Clicked => {
                     *color = Color::rgb(0.1, 0.1, 0.1).into();
+                    sound_event.send(PlaySoundEvent::new("ui_click"));
                     story.make_choice(choice.choice.to_owned());
                     story.resume_story_with_event(&mut event_writer)
                 }
@@ -1,10 +1,15 @@
+use crate::activation::ElementGroups;
+use crate::ink::{condition::Condition, ink_story::InkStory};
 use crate::interactive_narrative::SetCurrentKnotEvent;
 use crate::loading_state::LoadedAssets;
 use crate::physics::GameCollisionLayers;
+use crate::player::PlayerControl;
+use crate::sfx::PlaySoundEvent;
 use crate::states::{GameMode, States};
+use bevy::ecs::{schedule::ShouldRun, system::SystemParam};
 use bevy::prelude::*;
 
-use bevy_ecs_ldtk::prelude::*;
+use bevy_ecs_ldtk::{ldtk::Level, prelude::*};
 
 use heron::prelude::*;
 
@@ -15,11 +20,13 @@ impl Plugin for LevelPlugin {
         app.add_plugin(LdtkPlugin)
             .add_event::<SetLevelEvent>()
             .add_event::<ActivationEvent>()
+            .add_event::<PortalLockedEvent>()
             .insert_resource(LevelSelection::Identifier("Level_0".into()))
             .insert_resource(LdtkSettings {
                 level_background: LevelBackground::Nonexistent,
                 ..default()
             })
+            .init_resource::<LevelLoadMode>()
             .add_system(set_level)
             .add_system_set(
                 SystemSet::on_update(GameMode::Exploration)
@@ -30,14 +37,18 @@ impl Plugin for LevelPlugin {
                     .with_system(start_level),
             )
             .add_system_set(
-                SystemSet::on_update(States::LoadingLevel)
-                    .with_system(build_walls)
+                SystemSet::new()
+                    .with_run_criteria(is_building_level)
                     .with_system(build_portals),
             )
+            .add_system_set(
+                SystemSet::on_update(States::InGame)
+                    .with_system(track_player_level)
+                    .with_system(clear_distant_elements),
+            )
             .add_system_set(
                 SystemSet::on_exit(States::InGame).with_system(exit_game),
             )
-            .add_system(set_activation)
             .add_system_to_stage(CoreStage::Last, deactivate_elements)
             .add_system_to_stage(CoreStage::Last, clear_level_elements);
     }
@@ -45,15 +56,171 @@ impl Plugin for LevelPlugin {
 
 pub struct SetLevelEvent(pub String);
 
+/// How much of the LDtk world is kept loaded around the player.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LevelLoadMode {
+    /// Only the selected level is spawned, and every level change goes
+    /// through `SetLevelEvent` and a full reload.
+    Single,
+    /// Levels are spawned at their world position along with their LDtk
+    /// neighbours, and the selected level follows the player.
+    Neighbours,
+}
+
+impl LevelLoadMode {
+    /// The mode a level asks for with its `LoadNeighbours` field.
+    pub fn for_level(level: &Level) -> Self {
+        let neighbours = level.field_instances.iter().any(|field| {
+            matches!(
+                (field.identifier.as_str(), &field.value),
+                ("LoadNeighbours", FieldValue::Bool(true))
+            )
+        });
+        if neighbours {
+            Self::Neighbours
+        } else {
+            Self::Single
+        }
+    }
+
+    fn spawn_behavior(self) -> LevelSpawnBehavior {
+        match self {
+            Self::Single => LevelSpawnBehavior::UseZeroTranslation,
+            Self::Neighbours => LevelSpawnBehavior::UseWorldTranslation {
+                load_level_neighbors: true,
+            },
+        }
+    }
+}
+
+impl Default for LevelLoadMode {
+    fn default() -> Self {
+        Self::Single
+    }
+}
+
+/// World space rectangle covered by a level once it has been spawned.
+#[derive(Debug, Clone, Copy)]
+pub struct LevelBounds {
+    pub min: Vec2,
+    pub max: Vec2,
+}
+
+impl LevelBounds {
+    pub fn new(level: &Level, mode: LevelLoadMode) -> Self {
+        let size = Vec2::new(level.px_wid as f32, level.px_hei as f32);
+        let min = match mode {
+            LevelLoadMode::Single => Vec2::ZERO,
+            LevelLoadMode::Neighbours => Vec2::new(
+                level.world_x as f32,
+                -(level.world_y as f32) - size.y,
+            ),
+        };
+        Self {
+            min,
+            max: min + size,
+        }
+    }
+
+    pub fn contains(&self, point: Vec2) -> bool {
+        point.x >= self.min.x
+            && point.x <= self.max.x
+            && point.y >= self.min.y
+            && point.y <= self.max.y
+    }
+}
+
+pub fn selected_level<'a>(
+    ldtk: &'a LdtkAsset,
+    selection: &LevelSelection,
+) -> Option<&'a Level> {
+    ldtk.project
+        .levels
+        .iter()
+        .enumerate()
+        .find(|(index, level)| selection.is_match(index, level))
+        .map(|(_, level)| level)
+}
+
+/// Run criteria for the systems that turn freshly spawned LDtk entities into
+/// game entities. With neighbouring levels enabled, levels keep streaming in
+/// while the player explores, so these also need to run in game.
+pub fn is_building_level(
+    state: Res<State<States>>,
+    mode: Res<LevelLoadMode>,
+) -> ShouldRun {
+    match (state.current(), *mode) {
+        (States::LoadingLevel, _)
+        | (States::InGame, LevelLoadMode::Neighbours) => ShouldRun::Yes,
+        _ => ShouldRun::No,
+    }
+}
+
+/// Finds the level an LDtk entity was spawned in, along with its world
+/// transform, by walking up the level/layer hierarchy.
+#[derive(SystemParam)]
+pub struct LevelLookup<'w, 's> {
+    parents: Query<'w, 's, (&'static Parent, &'static Transform)>,
+    levels: Query<'w, 's, (&'static Handle<LdtkLevel>, &'static Transform)>,
+    layers: Query<'w, 's, &'static LayerMetadata>,
+    ldtk_levels: Res<'w, Assets<LdtkLevel>>,
+}
+
+impl<'w, 's> LevelLookup<'w, 's> {
+    pub fn locate(&self, entity: Entity) -> Option<(String, Transform)> {
+        let mut transform = Transform::identity();
+        let mut current = entity;
+        loop {
+            if let Ok((handle, level_transform)) = self.levels.get(current) {
+                let level = self.ldtk_levels.get(handle)?;
+                return Some((
+                    level.level.identifier.clone(),
+                    level_transform.mul_transform(transform),
+                ));
+            }
+            let (parent, local) = self.parents.get(current).ok()?;
+            transform = local.mul_transform(transform);
+            current = parent.get();
+        }
+    }
+
+    /// Grid size of the layer an LDtk entity was spawned in.
+    pub fn grid_size(&self, entity: Entity) -> Option<f32> {
+        let (parent, _) = self.parents.get(entity).ok()?;
+        let layer = self.layers.get(parent.get()).ok()?;
+        Some(layer.grid_size as f32)
+    }
+}
+
+/// Switches to another level, in the load mode that level asks for.
 fn set_level(
     mut commands: Commands,
     mut events: EventReader<SetLevelEvent>,
     mut app_state: ResMut<State<States>>,
     mut game_mode: ResMut<State<GameMode>>,
+    mut mode: ResMut<LevelLoadMode>,
+    mut settings: ResMut<LdtkSettings>,
+    assets: Res<LoadedAssets>,
+    ldtk_assets: Res<Assets<LdtkAsset>>,
 ) {
     let event = events.iter().last();
 
     if let Some(SetLevelEvent(level)) = event {
+        let next_mode = ldtk_assets
+            .get(&assets.test_level)
+            .and_then(|ldtk| {
+                ldtk.project
+                    .levels
+                    .iter()
+                    .find(|candidate| &candidate.identifier == level)
+            })
+            .map_or(LevelLoadMode::Single, LevelLoadMode::for_level);
+        if *mode != next_mode {
+            bevy::log::info!("Loading {} in {:?} mode", level, next_mode);
+            *mode = next_mode;
+        }
+        settings.level_spawn_behavior = mode.spawn_behavior();
+
         commands.insert_resource(LevelSelection::Identifier(level.into()));
         app_state.set(States::LoadingLevel);
         game_mode.set(GameMode::None);
@@ -68,6 +235,10 @@ pub struct LevelElement;
 #[component(storage = "SparseSet")]
 pub struct ClearLevelElement;
 
+/// Identifier of the level a `LevelElement` was spawned from.
+#[derive(Component)]
+pub struct OwningLevel(pub String);
+
 #[derive(Component)]
 pub struct ActiveElement;
 
@@ -86,6 +257,16 @@ pub enum Portal {
     Knot(String)
 }
 
+/// Story condition a portal needs before it lets the player through.
+#[derive(Component)]
+pub struct PortalCondition {
+    pub condition: Condition,
+    pub locked_knot: Option<String>,
+}
+
+/// Sent when the player touches a portal whose condition isn't met.
+pub struct PortalLockedEvent(pub Entity);
+
 fn start_level(
     mut commands: Commands,
     assets: Res<LoadedAssets>,
@@ -103,6 +284,84 @@ fn start_level(
         .insert(LevelElement);
 }
 
+fn track_player_level(
+    mode: Res<LevelLoadMode>,
+    mut selection: ResMut<LevelSelection>,
+    assets: Res<LoadedAssets>,
+    ldtk_assets: Res<Assets<LdtkAsset>>,
+    players: Query<&GlobalTransform, With<PlayerControl>>,
+) {
+    if *mode != LevelLoadMode::Neighbours {
+        return;
+    }
+    let (ldtk, player) =
+        match (ldtk_assets.get(&assets.test_level), players.get_single()) {
+            (Some(ldtk), Ok(player)) => (ldtk, player),
+            _ => return,
+        };
+    let position = player.translation().truncate();
+
+    let level = ldtk
+        .project
+        .levels
+        .iter()
+        .find(|level| LevelBounds::new(level, *mode).contains(position));
+
+    if let Some(level) = level {
+        if let LevelSelection::Identifier(current) = &*selection {
+            if current == &level.identifier {
+                return;
+            }
+        }
+        bevy::log::info!("Player entered level {}", &level.identifier);
+        *selection = LevelSelection::Identifier(level.identifier.clone());
+    }
+}
+
+fn clear_distant_elements(
+    mut commands: Commands,
+    mode: Res<LevelLoadMode>,
+    selection: Res<LevelSelection>,
+    assets: Res<LoadedAssets>,
+    ldtk_assets: Res<Assets<LdtkAsset>>,
+    elements: Query<
+        (Entity, &OwningLevel),
+        (With<LevelElement>, Without<ClearLevelElement>),
+    >,
+) {
+    if *mode != LevelLoadMode::Neighbours || !selection.is_changed() {
+        return;
+    }
+    let ldtk = match ldtk_assets.get(&assets.test_level) {
+        Some(ldtk) => ldtk,
+        None => return,
+    };
+    let current = match selected_level(ldtk, &selection) {
+        Some(level) => level,
+        None => return,
+    };
+
+    let in_range: Vec<&str> = ldtk
+        .project
+        .levels
+        .iter()
+        .filter(|level| {
+            level.uid == current.uid
+                || current
+                    .neighbours
+                    .iter()
+                    .any(|neighbour| neighbour.level_uid == level.uid)
+        })
+        .map(|level| level.identifier.as_str())
+        .collect();
+
+    for (entity, OwningLevel(level)) in elements.iter() {
+        if !in_range.contains(&level.as_str()) {
+            commands.entity(entity).insert(ClearLevelElement);
+        }
+    }
+}
+
 fn exit_game(
     mut commands: Commands,
     elements: Query<Entity, With<LevelElement>>,
@@ -132,51 +391,13 @@ fn deactivate_elements(
     }
 }
 
-fn set_activation(mut commands: Commands, elements: Query<(Entity, &NamedElement)>, mut event_reader: EventReader<ActivationEvent>) {
-    for event in event_reader.iter() {
-        bevy::log::info!("Activation: {} {}", &event.0, &event.1);
-        for (entity, name) in elements.iter() {
-            bevy::log::info!("Entity name {}", &name.0);
-            if name.0 == event.1 {
-                bevy::log::info!("Found entity");
-                if event.0 {
-                    commands.entity(entity).insert(ActiveElement);
-                } else {
-                    commands.entity(entity).insert(DeactivateElement);
-                }
-                break;
-            }
-        }
-    }
-}
-
-fn build_walls(
-    mut commands: Commands,
-    cells: Query<(Entity, &IntGridCell), Added<IntGridCell>>,
-) {
-    for (entity, cell) in cells.iter() {
-        if cell.value == 2 {
-            commands
-                .entity(entity)
-                .insert(RigidBody::Static)
-                .insert(CollisionShape::Cuboid {
-                    half_extends: Vec3::new(36., 36., 0.),
-                    border_radius: None,
-                })
-                .insert(
-                    CollisionLayers::all_masks::<GameCollisionLayers>()
-                        .with_group(GameCollisionLayers::World),
-                );
-        }
-    }
-}
-
 fn build_portals(
     mut commands: Commands,
     entities: Query<
         (Entity, &EntityInstance, &Transform),
         Added<EntityInstance>,
     >,
+    levels: LevelLookup,
 ) {
     for (entity, instance, _transform) in entities.iter() {
         if instance.identifier == "Portal" {
@@ -186,6 +407,9 @@ fn build_portals(
             let mut target_knot =  None;
             let mut has_target = false;
             let mut solid = true;
+            let mut groups = vec![];
+            let mut condition = None;
+            let mut locked_knot = None;
 
             for field in instance.field_instances.iter() {
                 match field.identifier.as_str() {
@@ -217,6 +441,28 @@ fn build_portals(
                             solid = *is_solid;
                         }
                     }
+                    "Groups" => {
+                        if let FieldValue::Strings(names) = &field.value {
+                            groups = names.iter().flatten().cloned().collect();
+                        }
+                    }
+                    "RequiresVariable" | "Condition" => {
+                        if let FieldValue::String(Some(source)) = &field.value {
+                            match Condition::parse(source) {
+                                Ok(parsed) => condition = Some(parsed),
+                                Err(err) => bevy::log::error!(
+                                    "Invalid portal condition {}: {}",
+                                    source,
+                                    err
+                                ),
+                            }
+                        }
+                    }
+                    "LockedKnot" => {
+                        if let FieldValue::String(Some(knot)) = &field.value {
+                            locked_knot = Some(knot.clone());
+                        }
+                    }
                     _ => {}
                 }
             }
@@ -239,6 +485,12 @@ fn build_portals(
                 if active {
                     entity_commands.insert(ActiveElement);
                 }
+                if let Some((level, _)) = levels.locate(entity) {
+                    entity_commands.insert(OwningLevel(level));
+                }
+                if !groups.is_empty() {
+                    entity_commands.insert(ElementGroups(groups));
+                }
                 if let Some(id) = id {
                     bevy::log::info!("Created named portal  {}", &id);
                     entity_commands.insert(NamedElement(id));
@@ -251,6 +503,12 @@ fn build_portals(
                 } else if let Some(knot) = target_knot {
                     entity_commands.insert(Portal::Knot(knot));
                 }
+                if let Some(condition) = condition {
+                    entity_commands.insert(PortalCondition {
+                        condition,
+                        locked_knot,
+                    });
+                }
             }
         }
     }
@@ -258,34 +516,156 @@ fn build_portals(
 
 fn trigger_portal(
     mut collisions: EventReader<CollisionEvent>,
-    portals: Query<&Portal, With<ActiveElement>>,
+    portals: Query<(&Portal, Option<&PortalCondition>), With<ActiveElement>>,
+    story: Option<Res<InkStory>>,
     mut set_level: EventWriter<SetLevelEvent>,
     mut set_knot: EventWriter<SetCurrentKnotEvent>,
+    mut locked: EventWriter<PortalLockedEvent>,
+    mut sounds: EventWriter<PlaySoundEvent>,
 ) {
     for event in collisions.iter().filter(|e| e.is_started()) {
         let (entity_1, entity_2) = event.rigid_body_entities();
         let (layers_1, layers_2) = event.collision_layers();
-        if layers_1.contains_group(GameCollisionLayers::Player)
+        let portal_entity = if layers_1
+            .contains_group(GameCollisionLayers::Player)
             && layers_2.contains_group(GameCollisionLayers::Portal)
         {
-            if let Ok(portal) = portals.get(entity_2) {
-                match portal {
-                    Portal::Level(level) => set_level.send(SetLevelEvent(level.clone())),
-                    Portal::Knot(knot) => set_knot.send(SetCurrentKnotEvent(Some(knot.clone()))),
-                }
-                break;
-            }
-        }
-        if layers_2.contains_group(GameCollisionLayers::Player)
+            entity_2
+        } else if layers_2.contains_group(GameCollisionLayers::Player)
             && layers_1.contains_group(GameCollisionLayers::Portal)
         {
-            if let Ok(portal) = portals.get(entity_1) {
-                match portal {
-                    Portal::Level(level) => set_level.send(SetLevelEvent(level.clone())),
-                    Portal::Knot(knot) => set_knot.send(SetCurrentKnotEvent(Some(knot.clone()))),
+            entity_1
+        } else {
+            continue;
+        };
+
+        if let Ok((portal, condition)) = portals.get(portal_entity) {
+            if let Some(PortalCondition {
+                condition,
+                locked_knot,
+            }) = condition
+            {
+                let unlocked = story
+                    .as_ref()
+                    .map_or(false, |story| condition.evaluate(story));
+                if !unlocked {
+                    bevy::log::info!("Portal is locked");
+                    locked.send(PortalLockedEvent(portal_entity));
+                    if let Some(knot) = locked_knot {
+                        set_knot.send(SetCurrentKnotEvent(Some(knot.clone())));
+                    }
+                    break;
                 }
-                break;
             }
+
+            match portal {
+                Portal::Level(level) => {
+                    sounds.send(PlaySoundEvent::new("portal"));
+                    set_level.send(SetLevelEvent(level.clone()))
+                }
+                Portal::Knot(knot) => {
+                    set_knot.send(SetCurrentKnotEvent(Some(knot.clone())))
+                }
+            }
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy_ecs_ldtk::ldtk::LdtkJson;
+
+    use super::*;
+
+    fn project() -> LdtkJson {
+        serde_json::from_str(include_str!("../assets/level-test.ldtk"))
+            .expect("level-test.ldtk should parse")
+    }
+
+    fn level<'a>(project: &'a LdtkJson, identifier: &str) -> &'a Level {
+        project
+            .levels
+            .iter()
+            .find(|level| level.identifier == identifier)
+            .expect("level should exist")
+    }
+
+    fn entities<'a>(
+        level: &'a Level,
+        identifier: &'a str,
+    ) -> impl Iterator<Item = &'a EntityInstance> {
+        level
+            .layer_instances
+            .iter()
+            .flatten()
+            .flat_map(|layer| layer.entity_instances.iter())
+            .filter(move |entity| entity.identifier == identifier)
+    }
+
+    /// Where an entity ends up once its level is spawned at its world
+    /// position.
+    fn world_position(level: &Level, entity: &EntityInstance) -> Vec2 {
+        Vec2::new(
+            (level.world_x + entity.px.x) as f32,
+            -(level.world_y + entity.px.y) as f32,
+        )
+    }
+
+    #[test]
+    fn levels_choose_their_load_mode() {
+        let project = project();
+        for (identifier, mode) in [
+            ("Level_0", LevelLoadMode::Single),
+            ("Lobby", LevelLoadMode::Neighbours),
+            ("CorpHallway", LevelLoadMode::Neighbours),
+        ] {
+            assert_eq!(
+                LevelLoadMode::for_level(level(&project, identifier)),
+                mode,
+                "{}",
+                identifier
+            );
         }
     }
+
+    #[test]
+    fn single_levels_are_bounded_from_the_origin() {
+        let project = project();
+        let lobby = level(&project, "Lobby");
+        let bounds = LevelBounds::new(lobby, LevelLoadMode::Single);
+        assert_eq!(bounds.min, Vec2::ZERO);
+        assert_eq!(
+            bounds.max,
+            Vec2::new(lobby.px_wid as f32, lobby.px_hei as f32)
+        );
+    }
+
+    #[test]
+    fn lobby_portal_arrives_inside_corp_hallway() {
+        let project = project();
+        let lobby = level(&project, "Lobby");
+        let hallway = level(&project, "CorpHallway");
+
+        let leads_to_hallway = entities(lobby, "Portal").any(|portal| {
+            portal.field_instances.iter().any(|field| {
+                matches!(
+                    (field.identifier.as_str(), &field.value),
+                    ("TargetLevel", FieldValue::String(Some(target)))
+                        if target == "CorpHallway"
+                )
+            })
+        });
+        assert!(leads_to_hallway);
+
+        // The player has to start inside the hallway, or following them
+        // would select the wrong level straight away
+        let player = entities(hallway, "Player")
+            .next()
+            .expect("CorpHallway should have a player start");
+        let position = world_position(hallway, player);
+        let mode = LevelLoadMode::for_level(hallway);
+        assert!(LevelBounds::new(hallway, mode).contains(position));
+        assert!(!LevelBounds::new(lobby, mode).contains(position));
+    }
 }
@@ -1,30 +1,56 @@
+mod activation;
 mod audio;
 mod camera;
+mod companion;
 mod ink;
 mod interactive_narrative;
 mod level;
 mod loading_state;
 mod menu;
+mod music;
+mod noise;
 mod physics;
+mod pixel_perfect;
 mod player;
+mod reveal;
+mod sfx;
 mod spirit;
+mod sprite_animation;
 mod states;
+mod steering;
+mod text_asset;
 pub mod theme;
+mod trigger;
+mod voice;
+mod walls;
+mod world_state;
 
+use activation::*;
 use audio::*;
 use bevy::{prelude::*, render::texture::ImageSettings};
 
 use camera::*;
+use companion::*;
 use heron::PhysicsPlugin;
 use ink::InkPlugin;
 use interactive_narrative::*;
 use level::*;
 use loading_state::*;
 use menu::*;
+use music::*;
+use pixel_perfect::PixelPerfectPlugin;
 use player::*;
+use reveal::*;
+use sfx::SfxPlugin;
 use spirit::*;
+use sprite_animation::SpriteAnimationPlugin;
 use states::{GameMode, States};
+use steering::*;
 use theme::*;
+use trigger::*;
+use voice::VoicePlugin;
+use walls::*;
+use world_state::*;
 
 pub fn app() -> App {
     let mut app = App::new();
@@ -40,12 +66,24 @@ pub fn app() -> App {
         .add_state(GameMode::None)
         .add_plugins(DefaultPlugins)
         .add_plugin(LevelPlugin)
+        .add_plugin(WallsPlugin)
+        .add_plugin(WorldStatePlugin)
+        .add_plugin(ActivationPlugin)
         .add_plugin(LoadingPlugin)
+        .add_plugin(SpriteAnimationPlugin)
         .add_plugin(MenuPlugin)
         .add_plugin(PlayerPlugin)
+        .add_plugin(CompanionPlugin)
         .add_plugin(SpiritPlugin)
+        .add_plugin(SteeringPlugin)
+        .add_plugin(RevealPlugin)
+        .add_plugin(TriggerPlugin)
         .add_plugin(AudioPlayerPlugin)
+        .add_plugin(MusicPlugin)
+        .add_plugin(SfxPlugin)
+        .add_plugin(VoicePlugin)
         .add_plugin(CameraPlugin)
+        .add_plugin(PixelPerfectPlugin)
         .add_plugin(PhysicsPlugin::default())
         // .add_plugin(WorldInspectorPlugin::new())
         .add_plugin(InkPlugin)
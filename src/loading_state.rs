@@ -3,7 +3,12 @@ use bevy_asset_loader::prelude::*;
 use bevy_ecs_ldtk::prelude::*;
 
 
-use crate::{ink::ink_asset::InkAsset, states::States};
+use crate::{
+    ink::ink_asset::InkAsset, music::presets_asset::MusicPresets,
+    sfx::sound_bank_asset::SoundBank,
+    sprite_animation::animation_asset::SpriteAnimations, states::States,
+    voice::voice_asset::VoiceManifest,
+};
 
 pub struct LoadingPlugin;
 
@@ -30,4 +35,16 @@ pub struct LoadedAssets {
 
     #[asset(path = "characters.png")]
     pub character_atlas: Handle<Image>,
+
+    #[asset(path = "characters.anim")]
+    pub character_animations: Handle<SpriteAnimations>,
+
+    #[asset(path = "music.mix")]
+    pub music_presets: Handle<MusicPresets>,
+
+    #[asset(path = "sounds.bank")]
+    pub sound_bank: Handle<SoundBank>,
+
+    #[asset(path = "lines.voice")]
+    pub voice_lines: Handle<VoiceManifest>,
 }
@@ -1,4 +1,5 @@
 use crate::level::SetLevelEvent;
+use crate::sfx::PlaySoundEvent;
 use crate::theme::*;
 use bevy::prelude::*;
 
@@ -116,12 +117,14 @@ fn button_system(
     >,
     mut text_query: Query<&mut Text>,
     mut event_writer: EventWriter<SetLevelEvent>,
+    mut sound_event: EventWriter<PlaySoundEvent>,
 ) {
     for (interaction, mut color, children) in &mut interaction_query {
         let _text = text_query.get_mut(children[0]).unwrap();
         match *interaction {
             Interaction::Clicked => {
                 *color = PRESSED_BUTTON.into();
+                sound_event.send(PlaySoundEvent::new("ui_click"));
                 event_writer.send(SetLevelEvent("Level_1".into()))
             }
             Interaction::Hovered => {
@@ -0,0 +1,99 @@
+use bevy::prelude::*;
+
+use crate::{
+    ink::ink_story::InkStory, interactive_narrative::SetCurrentKnotEvent,
+    loading_state::LoadedAssets,
+};
+
+use super::{presets_asset::*, MusicMixer};
+
+/// Sent by `#music:<preset>` tags. `None` (from `#music:auto`) hands the
+/// choice back to the preset rules.
+pub struct MusicCueEvent(pub Option<String>);
+
+/// Picks the stem mix from the story, using the rules in the presets file
+/// unless a `#music:` tag has chosen one.
+#[derive(Default)]
+pub struct MusicDirector {
+    forced: Option<String>,
+    /// The last knot the game moved the story to.
+    knot: Option<String>,
+    current: Option<String>,
+}
+
+impl MusicDirector {
+    pub fn current(&self) -> Option<&str> {
+        self.current.as_deref()
+    }
+
+    fn choose(
+        &self,
+        presets: &MusicPresets,
+        story: Option<&InkStory>,
+    ) -> Option<String> {
+        if self.forced.is_some() {
+            return self.forced.clone();
+        }
+        presets
+            .rules
+            .iter()
+            .rev()
+            .find(|rule| match &rule.trigger {
+                MusicTrigger::Knot(knot) => self.knot.as_ref() == Some(knot),
+                MusicTrigger::Condition(condition) => {
+                    story.map_or(false, |story| condition.evaluate(story))
+                }
+            })
+            .map(|rule| rule.preset.clone())
+            .or_else(|| presets.default.clone())
+    }
+}
+
+pub(super) fn follow_story(
+    mut director: ResMut<MusicDirector>,
+    mut cues: EventReader<MusicCueEvent>,
+    mut knots: EventReader<SetCurrentKnotEvent>,
+) {
+    for MusicCueEvent(preset) in cues.iter() {
+        bevy::log::info!("Music cue {:?}", preset);
+        director.forced = preset.clone();
+    }
+    for SetCurrentKnotEvent(knot) in knots.iter() {
+        if let Some(knot) = knot {
+            director.knot = Some(knot.clone());
+        }
+    }
+}
+
+pub(super) fn direct_music(
+    mut director: ResMut<MusicDirector>,
+    mut mixer: ResMut<MusicMixer>,
+    assets: Res<LoadedAssets>,
+    presets: Res<Assets<MusicPresets>>,
+    story: Option<Res<InkStory>>,
+) {
+    let presets = match presets.get(&assets.music_presets) {
+        Some(presets) => presets,
+        None => return,
+    };
+    let chosen = director.choose(presets, story.as_deref());
+    if chosen == director.current {
+        return;
+    }
+
+    match chosen.as_ref().and_then(|name| presets.presets.get(name)) {
+        Some(preset) => {
+            bevy::log::info!("Music preset {:?}", &chosen);
+            mixer.crossfade_to(preset);
+        }
+        None => {
+            bevy::log::warn!("No music preset called {:?}", &chosen);
+            mixer.crossfade_to(&MixPreset::default());
+        }
+    }
+    director.current = chosen;
+}
+
+pub(super) fn reset_director(mut director: ResMut<MusicDirector>) {
+    *director = MusicDirector::default();
+}
@@ -0,0 +1,459 @@
+use std::time::Duration;
+
+use bevy::{asset::LoadState, prelude::*};
+use bevy_kira_audio::prelude::*;
+
+use crate::{
+    audio::{AudioSpiritVolume, ConversationAudio},
+    sfx::{sound_bank_asset::MUSIC_BUS, SoundBuses},
+    states::{GameMode, States},
+};
+
+use self::{director::*, presets_asset::*};
+
+pub mod director;
+pub mod presets_asset;
+
+pub struct MusicPlugin;
+
+impl Plugin for MusicPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_asset::<MusicPresets>()
+            .init_asset_loader::<MusicPresetsLoader>()
+            .add_event::<MusicCueEvent>()
+            .init_resource::<MusicDirector>()
+            .add_startup_system(load_stems)
+            .add_system_set(
+                SystemSet::on_update(States::InGame)
+                    .with_system(start_stems)
+                    .with_system(pause_stems)
+                    .with_system(follow_story)
+                    .with_system(direct_music),
+            )
+            .add_system_set(
+                SystemSet::on_enter(States::Menu)
+                    .with_system(stop_stems)
+                    .with_system(reset_director),
+            )
+            .add_system_to_stage(CoreStage::PostUpdate, mix_stems);
+    }
+}
+
+/// The stems of the jam song, by short name and file.
+const STEMS: [(&str, &str); 12] = [
+    ("drums1", "bevy jam song - 0002 - Instrument - Drums1.ogg"),
+    ("bass1", "bevy jam song - 0003 - Instrument - Bass 1.ogg"),
+    ("piano", "bevy jam song - 0004 - Instrument - Piano 1.ogg"),
+    ("sax", "bevy jam song - 0005 - Instrument - Sax.ogg"),
+    ("drums2", "bevy jam song - 0006 - Instrument - Drums 2.ogg"),
+    ("strings", "bevy jam song - 0007 - Instrument - Strings.ogg"),
+    (
+        "clarinet",
+        "bevy jam song - 0008 - Instrument - Clarinet.ogg",
+    ),
+    ("keys", "bevy jam song - 0009 - Instrument - Keys.ogg"),
+    ("bass2", "bevy jam song - 0010 - Instrument - Bass 2.ogg"),
+    ("drums3", "bevy jam song - 0011 - Instrument - Drums 3.ogg"),
+    ("guitar", "bevy jam song - 0012 - Instrument - Guitar.ogg"),
+    (
+        "guitar_lead",
+        "bevy jam song - 0013 - Instrument - Guitar Lead.ogg",
+    ),
+];
+
+/// How quickly stem volume and pan catch up with what emitters ask for, per
+/// second.
+const STEM_SMOOTHING: f32 = 8.;
+
+/// How long stems take to fade when they're paused, resumed or stopped.
+const STEM_FADE: Duration = Duration::from_millis(400);
+
+pub struct Stem {
+    pub name: String,
+    pub file: String,
+    source: Handle<AudioSource>,
+    instance: Option<Handle<AudioInstance>>,
+    /// What emitters have asked for this frame.
+    gain: f32,
+    pan: f32,
+    /// Whether the speaking spirit plays this stem, so it isn't ducked.
+    focused: bool,
+    volume: f32,
+    panning: f32,
+    /// The stem's place in the director's mix, crossfading from `fade_from`
+    /// to `fade_to`.
+    fade_from: StemMix,
+    fade_to: StemMix,
+    fade_time: f32,
+    fade_progress: f32,
+}
+
+impl Stem {
+    fn new(name: &str, file: &str, source: Handle<AudioSource>) -> Self {
+        Self {
+            name: name.to_string(),
+            file: file.to_string(),
+            source,
+            instance: None,
+            gain: 0.,
+            pan: 0.5,
+            focused: false,
+            volume: 0.,
+            panning: 0.5,
+            fade_from: StemMix::default(),
+            fade_to: StemMix::default(),
+            fade_time: 0.,
+            fade_progress: 1.,
+        }
+    }
+
+    fn mix(&self) -> StemMix {
+        let t = self.fade_progress.clamp(0., 1.);
+        StemMix {
+            spatial: self.fade_from.spatial
+                + (self.fade_to.spatial - self.fade_from.spatial) * t,
+            bed: self.fade_from.bed
+                + (self.fade_to.bed - self.fade_from.bed) * t,
+        }
+    }
+}
+
+/// Plays every stem of the song together on one clock, so they stay in time
+/// however emitters come and go. Emitters ask for a stem's volume and pan
+/// each frame rather than playing audio themselves.
+pub struct MusicMixer {
+    pub stems: Vec<Stem>,
+    playing: bool,
+    paused: bool,
+}
+
+impl MusicMixer {
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    pub fn stem_for_file(&self, file: &str) -> Option<&Stem> {
+        self.stems.iter().find(|stem| stem.file == file)
+    }
+
+    /// Crossfades every stem from wherever it is now to its level in a
+    /// preset.
+    pub fn crossfade_to(&mut self, preset: &MixPreset) {
+        for stem in self.stems.iter_mut() {
+            stem.fade_from = stem.mix();
+            stem.fade_to = preset.stem(&stem.name);
+            stem.fade_time = preset.fade;
+            stem.fade_progress = 0.;
+        }
+    }
+
+    /// Asks for a stem to be heard this frame. When several emitters share a
+    /// stem, the loudest one wins.
+    pub fn request(&mut self, file: &str, gain: f32, pan: f32) {
+        if let Some(stem) = self.stems.iter_mut().find(|stem| stem.file == file)
+        {
+            if gain >= stem.gain {
+                stem.gain = gain;
+                stem.pan = pan;
+            }
+        }
+    }
+
+    /// Silences every stem and forgets their playing instances, returning
+    /// them to be stopped. `start_stems` starts them all again together.
+    fn reset(&mut self) -> Vec<Handle<AudioInstance>> {
+        let instances = self
+            .stems
+            .iter_mut()
+            .filter_map(|stem| {
+                stem.gain = 0.;
+                stem.volume = 0.;
+                stem.instance.take()
+            })
+            .collect();
+        self.playing = false;
+        self.paused = false;
+        instances
+    }
+
+    /// Brings a stem forward over the ducked music this frame, for the
+    /// spirit that's speaking.
+    pub fn focus(&mut self, file: &str) {
+        if let Some(stem) = self.stems.iter_mut().find(|stem| stem.file == file)
+        {
+            stem.focused = true;
+        }
+    }
+}
+
+fn load_stems(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.insert_resource(MusicMixer {
+        stems: STEMS
+            .iter()
+            .map(|(name, file)| Stem::new(name, file, asset_server.load(*file)))
+            .collect(),
+        playing: false,
+        paused: false,
+    });
+}
+
+/// Starts all the stems in the same frame, once every one of them has
+/// loaded - otherwise they'd each start whenever their file was ready.
+fn start_stems(
+    mut mixer: ResMut<MusicMixer>,
+    audio: Res<Audio>,
+    asset_server: Res<AssetServer>,
+) {
+    if mixer.playing {
+        return;
+    }
+    let state = asset_server
+        .get_group_load_state(mixer.stems.iter().map(|stem| stem.source.id));
+    match state {
+        LoadState::Loaded => {}
+        LoadState::Failed => {
+            bevy::log::error!("Couldn't load the music stems");
+            mixer.playing = true;
+            return;
+        }
+        _ => return,
+    }
+
+    bevy::log::info!("Starting {} music stems", mixer.stems.len());
+    for stem in mixer.stems.iter_mut() {
+        stem.instance = Some(
+            audio
+                .play(stem.source.clone())
+                .looped()
+                .with_volume(0.)
+                .handle(),
+        );
+        stem.volume = 0.;
+    }
+    mixer.playing = true;
+}
+
+fn stems_paused(mode: &GameMode, conversation: ConversationAudio) -> bool {
+    match mode {
+        GameMode::Conversation => conversation == ConversationAudio::Pause,
+        _ => false,
+    }
+}
+
+/// Pauses the stems during conversations if the `ConversationAudio` policy
+/// asks for it. Pausing keeps them in time with each other, so they pick up
+/// where they left off.
+fn pause_stems(
+    mut mixer: ResMut<MusicMixer>,
+    mut instances: ResMut<Assets<AudioInstance>>,
+    game_mode: Res<State<GameMode>>,
+    conversation: Res<ConversationAudio>,
+) {
+    let paused = stems_paused(game_mode.current(), *conversation);
+    if paused == mixer.paused {
+        return;
+    }
+
+    for stem in mixer.stems.iter() {
+        if let Some(instance) =
+            stem.instance.as_ref().and_then(|h| instances.get_mut(h))
+        {
+            let tween = AudioTween::linear(STEM_FADE);
+            if paused {
+                instance.pause(tween);
+            } else {
+                instance.resume(tween);
+            }
+        }
+    }
+    mixer.paused = paused;
+}
+
+/// Stops the stems on going back to the menu. Loading another level leaves
+/// them playing, so the song carries on in time across levels.
+fn stop_stems(
+    mut mixer: ResMut<MusicMixer>,
+    mut instances: ResMut<Assets<AudioInstance>>,
+) {
+    for handle in mixer.reset() {
+        if let Some(instance) = instances.get_mut(&handle) {
+            instance.stop(AudioTween::linear(STEM_FADE));
+        }
+        instances.remove(&handle);
+    }
+}
+
+fn mix_stems(
+    mixer: Option<ResMut<MusicMixer>>,
+    mut instances: ResMut<Assets<AudioInstance>>,
+    spirit_volume: Res<AudioSpiritVolume>,
+    buses: Res<SoundBuses>,
+    time: Res<Time>,
+) {
+    let mut mixer = match mixer {
+        Some(mixer) => mixer,
+        None => return,
+    };
+    let smoothing = 1. - (-STEM_SMOOTHING * time.delta_seconds()).exp();
+
+    let delta = time.delta_seconds();
+
+    for stem in mixer.stems.iter_mut() {
+        stem.fade_progress = if stem.fade_time > 0. {
+            (stem.fade_progress + delta / stem.fade_time).min(1.)
+        } else {
+            1.
+        };
+        let mix = stem.mix();
+        let spatial = stem.gain * mix.spatial;
+        let bus = if stem.focused {
+            buses.level(MUSIC_BUS) * buses.focus()
+        } else {
+            buses.volume(MUSIC_BUS)
+        };
+        let target =
+            ((spatial + mix.bed) * spirit_volume.0 * bus).clamp(0., 1.);
+        stem.volume += (target - stem.volume) * smoothing;
+        // The bed is heard from everywhere, so it pulls the pan to the
+        // middle. Keep the pan where it was while a stem fades out.
+        if spatial + mix.bed > 0. {
+            let pan =
+                (spatial * stem.pan + mix.bed * 0.5) / (spatial + mix.bed);
+            stem.panning += (pan - stem.panning) * smoothing;
+        }
+        stem.gain = 0.;
+        stem.focused = false;
+
+        if let Some(instance) =
+            stem.instance.as_ref().and_then(|h| instances.get_mut(h))
+        {
+            instance.set_volume(stem.volume.into(), AudioTween::default());
+            instance.set_panning(stem.panning.into(), AudioTween::default());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::asset::AssetPlugin;
+
+    use super::*;
+
+    fn mixer() -> MusicMixer {
+        MusicMixer {
+            stems: vec![
+                Stem::new("drums1", "drums1.ogg", Handle::default()),
+                Stem::new("bass1", "bass1.ogg", Handle::default()),
+            ],
+            playing: false,
+            paused: false,
+        }
+    }
+
+    #[test]
+    fn exploring_leaves_stems_playing() {
+        assert!(!stems_paused(
+            &GameMode::Exploration,
+            ConversationAudio::Pause
+        ));
+    }
+
+    #[test]
+    fn conversations_pause_stems_by_policy() {
+        assert!(stems_paused(
+            &GameMode::Conversation,
+            ConversationAudio::Pause
+        ));
+        assert!(!stems_paused(
+            &GameMode::Conversation,
+            ConversationAudio::Duck
+        ));
+        assert!(!stems_paused(
+            &GameMode::Conversation,
+            ConversationAudio::Continue
+        ));
+    }
+
+    #[test]
+    fn reset_stops_every_playing_stem() {
+        let mut mixer = mixer();
+        for stem in mixer.stems.iter_mut() {
+            stem.instance = Some(Handle::default());
+            stem.gain = 1.;
+            stem.volume = 1.;
+        }
+        mixer.playing = true;
+        mixer.paused = true;
+
+        assert_eq!(mixer.reset().len(), 2);
+        assert!(!mixer.is_playing());
+        assert!(!mixer.paused);
+        for stem in mixer.stems.iter() {
+            assert!(stem.instance.is_none());
+            assert_eq!(stem.volume, 0.);
+            assert_eq!(stem.gain, 0.);
+        }
+    }
+
+    #[test]
+    fn reset_skips_stems_that_never_started() {
+        let mut mixer = mixer();
+        mixer.stems[0].instance = Some(Handle::default());
+
+        assert_eq!(mixer.reset().len(), 1);
+        assert!(mixer.reset().is_empty());
+    }
+
+    /// An app in game with every stem playing, running the systems the
+    /// plugin adds for leaving the game.
+    fn app_in_game() -> App {
+        let mut mixer = mixer();
+        for stem in mixer.stems.iter_mut() {
+            stem.instance = Some(Handle::default());
+        }
+        mixer.playing = true;
+
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins)
+            .add_plugin(AssetPlugin)
+            .add_asset::<AudioInstance>()
+            .add_state(States::InGame)
+            .insert_resource(mixer)
+            .init_resource::<MusicDirector>()
+            .add_system_set(
+                SystemSet::on_enter(States::Menu)
+                    .with_system(stop_stems)
+                    .with_system(reset_director),
+            );
+        app.update();
+        app
+    }
+
+    fn go_to(app: &mut App, state: States) {
+        app.world
+            .resource_mut::<State<States>>()
+            .set(state)
+            .unwrap();
+        app.update();
+    }
+
+    #[test]
+    fn returning_to_the_menu_stops_the_stems() {
+        let mut app = app_in_game();
+        go_to(&mut app, States::Menu);
+
+        let mixer = app.world.resource::<MusicMixer>();
+        assert!(!mixer.is_playing());
+        assert!(mixer.stems.iter().all(|stem| stem.instance.is_none()));
+    }
+
+    #[test]
+    fn loading_another_level_leaves_the_stems_playing() {
+        let mut app = app_in_game();
+        go_to(&mut app, States::LoadingLevel);
+
+        let mixer = app.world.resource::<MusicMixer>();
+        assert!(mixer.is_playing());
+        assert!(mixer.stems.iter().all(|stem| stem.instance.is_some()));
+    }
+}
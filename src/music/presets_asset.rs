@@ -0,0 +1,286 @@
+use std::collections::HashMap;
+
+use bevy::reflect::TypeUuid;
+
+use crate::{
+    ink::condition::Condition,
+    text_asset::{lines, Line, TextAsset, TextAssetLoader},
+};
+
+/// How loud a stem is in a mix. `spatial` scales the stem as heard from its
+/// spirits, and `bed` plays it everywhere regardless of position.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StemMix {
+    pub spatial: f32,
+    pub bed: f32,
+}
+
+impl Default for StemMix {
+    fn default() -> Self {
+        Self {
+            spatial: 1.,
+            bed: 0.,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MixPreset {
+    /// Seconds to crossfade into this preset.
+    pub fade: f32,
+    /// Mix for stems that aren't listed.
+    pub default: StemMix,
+    pub stems: HashMap<String, StemMix>,
+}
+
+impl Default for MixPreset {
+    fn default() -> Self {
+        Self {
+            fade: 2.,
+            default: StemMix::default(),
+            stems: HashMap::new(),
+        }
+    }
+}
+
+impl MixPreset {
+    pub fn stem(&self, name: &str) -> StemMix {
+        self.stems.get(name).copied().unwrap_or(self.default)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum MusicTrigger {
+    Condition(Condition),
+    Knot(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct MusicRule {
+    pub trigger: MusicTrigger,
+    pub preset: String,
+}
+
+/// Named stem mixes and the story rules that choose between them.
+///
+/// ```text
+/// [preset tension]
+/// fade 1.5
+/// default 0.6
+/// drums2 1 0.35
+///
+/// [rules]
+/// default explore
+/// when knows_it_was_unstable => tension
+/// knot deduction => deduction
+/// ```
+#[derive(Debug, TypeUuid)]
+#[uuid = "b1e0f4a2-7c3d-4e8f-9a16-2d5c8e7f3b40"]
+pub struct MusicPresets {
+    pub presets: HashMap<String, MixPreset>,
+    pub rules: Vec<MusicRule>,
+    pub default: Option<String>,
+}
+
+enum Section {
+    Preset(String),
+    Rules,
+}
+
+fn parse_mix(line: &Line, words: &[&str]) -> Result<StemMix, String> {
+    match words {
+        [spatial] => Ok(StemMix {
+            spatial: line.parse("gain", spatial)?,
+            bed: 0.,
+        }),
+        [spatial, bed] => Ok(StemMix {
+            spatial: line.parse("gain", spatial)?,
+            bed: line.parse("gain", bed)?,
+        }),
+        _ => Err(line.error("Expected a spatial gain and optional bed gain")),
+    }
+}
+
+impl TextAsset for MusicPresets {
+    const EXTENSIONS: &'static [&'static str] = &["mix"];
+
+    fn parse(source: &str) -> Result<Self, String> {
+        let mut presets = HashMap::new();
+        let mut rules = vec![];
+        let mut default = None;
+        let mut section = None;
+
+        for line in lines(source) {
+            let line = line?;
+            let error = |message: String| line.error(message);
+
+            if let Some(header) = line.header() {
+                section = match header.split_whitespace().collect::<Vec<_>>()[..]
+                {
+                    ["preset", name] => {
+                        presets.insert(name.to_string(), MixPreset::default());
+                        Some(Section::Preset(name.to_string()))
+                    }
+                    ["rules"] => Some(Section::Rules),
+                    _ => {
+                        return Err(error(format!(
+                            "Unknown section {}",
+                            header
+                        )))
+                    }
+                };
+                continue;
+            }
+
+            match &section {
+                Some(Section::Preset(name)) => {
+                    let preset = presets.get_mut(name).unwrap();
+                    let words = line.words();
+                    match words[..] {
+                        ["fade", seconds] => {
+                            preset.fade = line.parse("fade", seconds)?;
+                        }
+                        ["default", ..] => {
+                            preset.default = parse_mix(&line, &words[1..])?;
+                        }
+                        [stem, ..] => {
+                            let mix = parse_mix(&line, &words[1..])?;
+                            preset.stems.insert(stem.to_string(), mix);
+                        }
+                        [] => {}
+                    }
+                }
+                Some(Section::Rules) => {
+                    if let Some(preset) = line.text.strip_prefix("default ") {
+                        default = Some(preset.trim().to_string());
+                        continue;
+                    }
+                    let (trigger, preset) =
+                        line.text.split_once("=>").ok_or_else(|| {
+                            error("Expected trigger => preset".to_string())
+                        })?;
+                    let trigger = trigger.trim();
+                    let trigger =
+                        if let Some(knot) = trigger.strip_prefix("knot ") {
+                            MusicTrigger::Knot(knot.trim().to_string())
+                        } else if let Some(condition) =
+                            trigger.strip_prefix("when ")
+                        {
+                            MusicTrigger::Condition(
+                                Condition::parse(condition).map_err(error)?,
+                            )
+                        } else {
+                            return Err(error(format!(
+                                "Unknown trigger {}",
+                                trigger
+                            )));
+                        };
+                    rules.push(MusicRule {
+                        trigger,
+                        preset: preset.trim().to_string(),
+                    });
+                }
+                None => {
+                    return Err(error("Line outside of a section".to_string()))
+                }
+            }
+        }
+
+        for preset in rules.iter().map(|rule| &rule.preset).chain(&default) {
+            if !presets.contains_key(preset) {
+                return Err(format!("Unknown preset {}", preset));
+            }
+        }
+
+        Ok(Self {
+            presets,
+            rules,
+            default,
+        })
+    }
+}
+
+pub type MusicPresetsLoader = TextAssetLoader<MusicPresets>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presets_and_rules_are_parsed() {
+        let source = "\
+[preset explore]
+default 1
+
+[preset tension]
+fade 1.5
+default 0.6
+drums2 1 0.35
+
+[rules]
+default explore
+when knows_it_was_unstable => tension
+knot deduction => explore
+";
+        let presets = MusicPresets::parse(source).unwrap();
+        assert_eq!(presets.default.as_deref(), Some("explore"));
+
+        let tension = &presets.presets["tension"];
+        assert_eq!(tension.fade, 1.5);
+        assert_eq!(
+            tension.stem("drums2"),
+            StemMix {
+                spatial: 1.,
+                bed: 0.35
+            }
+        );
+        assert_eq!(
+            tension.stem("piano"),
+            StemMix {
+                spatial: 0.6,
+                bed: 0.
+            }
+        );
+        assert_eq!(presets.presets["explore"].fade, 2.);
+
+        assert_eq!(presets.rules.len(), 2);
+        assert!(matches!(
+            presets.rules[0].trigger,
+            MusicTrigger::Condition(_)
+        ));
+        assert_eq!(presets.rules[0].preset, "tension");
+        assert!(matches!(
+            &presets.rules[1].trigger,
+            MusicTrigger::Knot(knot) if knot == "deduction"
+        ));
+    }
+
+    #[test]
+    fn unknown_sections_and_triggers_are_errors() {
+        let error = MusicPresets::parse("[presets]");
+        assert_eq!(error.unwrap_err(), "Line 1: Unknown section presets");
+        let error = MusicPresets::parse("[preset a]\n[rules]\nafter x => a");
+        assert_eq!(error.unwrap_err(), "Line 3: Unknown trigger after x");
+        let error = MusicPresets::parse("[rules]\ndefault quiet");
+        assert_eq!(error.unwrap_err(), "Unknown preset quiet");
+    }
+
+    #[test]
+    fn bad_numbers_are_errors() {
+        let error = MusicPresets::parse("[preset a]\nfade slow");
+        assert_eq!(error.unwrap_err(), "Line 2: Invalid fade slow");
+        let error = MusicPresets::parse("[preset a]\ndrums loud");
+        assert_eq!(error.unwrap_err(), "Line 2: Invalid gain loud");
+        let error = MusicPresets::parse("[preset a]\ndrums 1 0.5 2");
+        assert_eq!(
+            error.unwrap_err(),
+            "Line 2: Expected a spatial gain and optional bed gain"
+        );
+    }
+
+    #[test]
+    fn duplicate_presets_are_errors() {
+        let error = MusicPresets::parse("[preset a]\nfade 1\n[preset a]");
+        assert_eq!(error.unwrap_err(), "Line 3: Duplicate section [preset a]");
+    }
+}
@@ -0,0 +1,8 @@
+/// Cheap xorshift noise in `0..1`, for jitter that doesn't need a proper
+/// rng. `seed` must start non-zero, and is advanced on each call.
+pub fn xorshift(seed: &mut u32) -> f32 {
+    *seed ^= *seed << 13;
+    *seed ^= *seed >> 17;
+    *seed ^= *seed << 5;
+    *seed as f32 / u32::MAX as f32
+}
@@ -1,9 +1,11 @@
 use heron::PhysicsLayer;
 
-#[derive(PhysicsLayer)]
+#[derive(PhysicsLayer, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum GameCollisionLayers {
     World,
     Player,
     Spirit,
     Portal,
+    Trigger,
+    Companion,
 }
@@ -0,0 +1,185 @@
+use bevy::{
+    core_pipeline::clear_color::ClearColorConfig,
+    prelude::*,
+    render::{
+        camera::RenderTarget,
+        render_resource::{
+            Extent3d, TextureDescriptor, TextureDimension, TextureFormat,
+            TextureUsages,
+        },
+        view::RenderLayers,
+    },
+    sprite::Rect,
+    transform::TransformSystem,
+    ui::UiCameraConfig,
+};
+
+use crate::camera::FollowCam;
+
+pub struct PixelPerfectPlugin;
+
+impl Plugin for PixelPerfectPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<PixelPerfect>()
+            .add_startup_system_to_stage(
+                StartupStage::PostStartup,
+                setup_render_target,
+            )
+            .add_system_to_stage(
+                CoreStage::PostUpdate,
+                present.before(TransformSystem::TransformPropagate),
+            );
+    }
+}
+
+/// Renders the game at a fixed low resolution and scales it up to the
+/// window by a whole number, with black bars around whatever's left over,
+/// so pixel art stays crisp at any window size.
+pub struct PixelPerfect {
+    pub enabled: bool,
+    /// Size of the view, in texels.
+    pub resolution: UVec2,
+    /// Let the camera move by screen pixels between texels. Otherwise it
+    /// snaps to whole texels, which is crisper but steps at slow speeds.
+    pub smooth: bool,
+}
+
+impl Default for PixelPerfect {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            resolution: UVec2::new(512, 288),
+            smooth: true,
+        }
+    }
+}
+
+/// Extra texels rendered around the view, so smooth movement has something
+/// to show at the edges.
+const MARGIN: f32 = 1.;
+
+/// Only the camera showing the upscaled view sees this layer.
+const DISPLAY_LAYER: u8 = 1;
+
+/// The sprite showing the game's render target.
+#[derive(Component)]
+struct PixelDisplay;
+
+fn setup_render_target(
+    mut commands: Commands,
+    settings: Res<PixelPerfect>,
+    mut images: ResMut<Assets<Image>>,
+    mut cameras: Query<(Entity, &mut Camera), With<FollowCam>>,
+) {
+    if !settings.enabled {
+        return;
+    }
+
+    let size = Extent3d {
+        width: settings.resolution.x + 2 * MARGIN as u32,
+        height: settings.resolution.y + 2 * MARGIN as u32,
+        ..default()
+    };
+    let mut image = Image {
+        texture_descriptor: TextureDescriptor {
+            label: None,
+            size,
+            dimension: TextureDimension::D2,
+            format: TextureFormat::Bgra8UnormSrgb,
+            mip_level_count: 1,
+            sample_count: 1,
+            usage: TextureUsages::TEXTURE_BINDING
+                | TextureUsages::COPY_DST
+                | TextureUsages::RENDER_ATTACHMENT,
+        },
+        ..default()
+    };
+    image.resize(size);
+    let image = images.add(image);
+
+    // The game camera draws into the image first, leaving the UI for the
+    // display camera to draw at full resolution.
+    for (entity, mut camera) in cameras.iter_mut() {
+        camera.target = RenderTarget::Image(image.clone());
+        camera.priority = -1;
+        commands
+            .entity(entity)
+            .insert(UiCameraConfig { show_ui: false });
+    }
+
+    commands
+        .spawn_bundle(SpriteBundle {
+            texture: image,
+            ..default()
+        })
+        .insert(PixelDisplay)
+        .insert(RenderLayers::layer(DISPLAY_LAYER));
+    commands
+        .spawn_bundle(Camera2dBundle {
+            camera_2d: Camera2d {
+                clear_color: ClearColorConfig::Custom(Color::BLACK),
+            },
+            ..default()
+        })
+        .insert(RenderLayers::layer(DISPLAY_LAYER));
+}
+
+/// Snaps the game camera to whole texels and fits the upscaled view to the
+/// window. With smooth movement on, the part of a texel the camera was
+/// snapped by is made up for by sliding which texels are shown.
+fn present(
+    settings: Res<PixelPerfect>,
+    windows: Res<Windows>,
+    mut cameras: Query<
+        (&mut Transform, &OrthographicProjection),
+        (With<FollowCam>, Without<PixelDisplay>),
+    >,
+    mut displays: Query<(&mut Transform, &mut Sprite), With<PixelDisplay>>,
+) {
+    if !settings.enabled {
+        return;
+    }
+    let window = match windows.get_primary() {
+        Some(window) => window,
+        None => return,
+    };
+
+    let mut remainder = Vec2::ZERO;
+    for (mut transform, projection) in cameras.iter_mut() {
+        let texel = projection.scale;
+        let position = transform.translation.truncate();
+        let snapped = (position / texel).round() * texel;
+        remainder = (position - snapped) / texel;
+        transform.translation.x = snapped.x;
+        transform.translation.y = snapped.y;
+    }
+
+    let resolution = settings.resolution.as_vec2();
+    let physical = Vec2::new(
+        window.physical_width() as f32,
+        window.physical_height() as f32,
+    );
+    let scale = (physical / resolution).min_element().floor().max(1.);
+    let scale_factor = window.scale_factor() as f32;
+
+    for (mut transform, mut sprite) in displays.iter_mut() {
+        // Texture rows run downwards, the opposite way to the world.
+        let offset = if settings.smooth {
+            Vec2::new(remainder.x, -remainder.y)
+        } else {
+            Vec2::ZERO
+        };
+        let min = Vec2::splat(MARGIN) + offset;
+        sprite.rect = Some(Rect {
+            min,
+            max: min + resolution,
+        });
+
+        // Line the view up with physical pixels when the window is an odd
+        // number of them across.
+        let odd = Vec2::new(physical.x % 2., physical.y % 2.);
+        transform.translation = (odd * 0.5 / scale_factor).extend(0.);
+        transform.scale =
+            Vec3::new(scale / scale_factor, scale / scale_factor, 1.);
+    }
+}
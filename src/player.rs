@@ -1,17 +1,21 @@
 use std::f32::consts::PI;
 
 use bevy::{prelude::*};
-use bevy_ecs_ldtk::{prelude::FieldValue, EntityInstance};
+use bevy_ecs_ldtk::{
+    prelude::{FieldValue, LdtkAsset, LevelSelection},
+    EntityInstance,
+};
 
 use heron::prelude::*;
 use leafwing_input_manager::prelude::*;
 
 use crate::{
     interactive_narrative::SetCurrentKnotEvent,
-    level::LevelElement,
+    level::{selected_level, LevelElement, LevelLookup},
     loading_state::LoadedAssets,
     physics::GameCollisionLayers,
     spirit::CharacterAtlas,
+    sprite_animation::{Facing, SpriteAnimator},
     states::{GameMode, States},
 };
 
@@ -23,8 +27,7 @@ impl Plugin for PlayerPlugin {
             .add_system_set(
                 SystemSet::on_update(States::InGame)
                     .with_system(spawn_player)
-                    .with_system(animate_player)
-                    .with_system(animate_companion),
+                    .with_system(animate_player),
             )
             .add_system_set(
                 SystemSet::on_update(GameMode::Exploration)
@@ -55,8 +58,9 @@ pub struct PlayerControl {
     rotate_speed: f32,
 }
 
+/// The visible part of the player, kept upright under the rotating body.
 #[derive(Component)]
-pub struct Companion;
+pub struct PlayerSprite;
 
 impl Default for PlayerControl {
     fn default() -> Self {
@@ -71,12 +75,19 @@ fn spawn_player(
     mut commands: Commands,
     _meshes: ResMut<Assets<Mesh>>,
     _materials: ResMut<Assets<ColorMaterial>>,
-    entities: Query<(&EntityInstance, &Transform), Added<EntityInstance>>,
+    entities: Query<
+        (Entity, &EntityInstance, &Transform),
+        Added<EntityInstance>,
+    >,
     mut event_writer: EventWriter<SetCurrentKnotEvent>,
     assets: Res<LoadedAssets>,
     _asset_server: Res<AssetServer>,
     texture_atlas: Option<Res<CharacterAtlas>>,
     mut texture_atlases: ResMut<Assets<TextureAtlas>>,
+    existing_players: Query<(), With<PlayerControl>>,
+    levels: LevelLookup,
+    selection: Res<LevelSelection>,
+    ldtk_assets: Res<Assets<LdtkAsset>>,
 ) {
     let atlas_handle = match texture_atlas {
         Some(atlas) => atlas.atlas.clone(),
@@ -95,8 +106,29 @@ fn spawn_player(
         }
     };
 
-    for (instance, transform) in entities.iter() {
+    // With neighbouring levels loaded, a level that streams back in would
+    // otherwise spawn a second player.
+    if !existing_players.is_empty() {
+        return;
+    }
+    // Several levels can spawn in the same frame, each with its own Player
+    // entity, so only the one in the selected level counts.
+    let selected = ldtk_assets
+        .get(&assets.test_level)
+        .and_then(|ldtk| selected_level(ldtk, &selection))
+        .map(|level| level.identifier.clone());
+
+    for (instance_entity, instance, transform) in entities.iter() {
         if instance.identifier == "Player" {
+            let located = levels.locate(instance_entity);
+            if let (Some(selected), Some((level, _))) = (&selected, &located) {
+                if level != selected {
+                    continue;
+                }
+            }
+            let transform = located
+                .map(|(_, transform)| transform)
+                .unwrap_or(*transform);
             let (move_speed, rotate_speed) = {
                 let mut move_speed = 10f32;
                 let mut rotate_speed = 10f32;
@@ -141,16 +173,12 @@ fn spawn_player(
                 (move_speed, rotate_speed * PI / 180.)
             };
 
+            // The body turns with the tank controls, while the sprite stays
+            // upright and picks frames for the direction it's facing
             commands
-                .spawn_bundle(SpriteSheetBundle {
-                    sprite: TextureAtlasSprite {
-                        index: PLAYER_IDLE_START,
-                        ..default()
-                    },
-                    texture_atlas: atlas_handle.clone(),
-                    transform: transform.with_scale(Vec3::ONE * 0.5),
-                    ..default()
-                })
+                .spawn_bundle(SpatialBundle::from_transform(
+                    transform.with_scale(Vec3::ONE * 0.5),
+                ))
                 .insert(LevelElement)
                 .insert(PlayerControl {
                     move_speed,
@@ -159,17 +187,11 @@ fn spawn_player(
                 .with_children(|parent| {
                     parent
                         .spawn_bundle(SpriteSheetBundle {
-                            sprite: TextureAtlasSprite {
-                                index: CASS_START,
-                                ..default()
-                            },
                             texture_atlas: atlas_handle.clone(),
-                            transform: Transform::default()
-                                .with_translation(Vec3::new(-3., -11.5, 1.))
-                                .with_scale(Vec3::new(0.8, 0.8, 0.8)),
                             ..default()
                         })
-                        .insert(Companion);
+                        .insert(SpriteAnimator::new("player", "idle"))
+                        .insert(PlayerSprite);
                 })
                 .insert(RigidBody::Dynamic)
                 .insert(CollisionShape::Sphere { radius: 16. })
@@ -184,6 +206,7 @@ fn spawn_player(
                     CollisionLayers::all_masks::<GameCollisionLayers>()
                         .with_group(GameCollisionLayers::Player),
                 );
+            break;
         }
     }
 }
@@ -270,50 +293,26 @@ fn stop_player(mut query: Query<&mut Velocity, With<PlayerControl>>) {
     }
 }
 
-const PLAYER_IDLE_START: usize = 3;
-const PLAYER_IDLE_LEN: usize = 5;
-
-const PLAYER_WALK_START: usize = 19;
-const PLAYER_WALK_LEN: usize = 4;
-
-const CASS_START: usize = 0;
-const CASS_LEN: usize = 3;
-
 fn animate_player(
-    mut players: Query<
-        (&mut TextureAtlasSprite, &Velocity),
-        With<PlayerControl>,
+    players: Query<(&Transform, &Velocity), With<PlayerControl>>,
+    mut sprites: Query<
+        (&Parent, &mut Transform, &mut SpriteAnimator),
+        (With<PlayerSprite>, Without<PlayerControl>),
     >,
-    time: Res<Time>,
 ) {
-    let time = (time.seconds_since_startup() * 5.) as usize;
-    for (mut sprite, velocity) in players.iter_mut() {
+    for (parent, mut transform, mut animator) in sprites.iter_mut() {
+        let (body, velocity) = match players.get(parent.get()) {
+            Ok(player) => player,
+            Err(_) => continue,
+        };
+        transform.rotation = body.rotation.inverse();
+
+        let forward = body.rotation.mul_vec3(Vec3::Y);
+        animator.facing = Some(Facing(forward.y.atan2(forward.x)));
         if velocity.linear.length() < 0.1 {
-            let current_index =
-                PLAYER_IDLE_START + ((time / 10) % PLAYER_IDLE_LEN);
-            sprite.index = current_index;
+            animator.play("idle");
         } else {
-            let current_index = PLAYER_WALK_START + (time % PLAYER_WALK_LEN);
-            sprite.index = current_index;
+            animator.play("walk");
         }
     }
 }
-
-fn animate_companion(
-    mut companions: Query<
-        (&mut TextureAtlasSprite, &mut Transform),
-        With<Companion>,
-    >,
-    time: Res<Time>,
-) {
-    let time = (time.seconds_since_startup() * 5.) as usize;
-    for (mut sprite, mut transform) in companions.iter_mut() {
-        let current_index = CASS_START + (time % CASS_LEN);
-        sprite.index = current_index;
-        let position_horizontal = ((time as f32) / 3.).sin() * 6.;
-        let position_vertical = ((time as f32) / 4.).cos() * 3.;
-
-        transform.translation = Vec3::new(-3., -11.5, 1.)
-            + Vec3::new(position_horizontal, position_vertical, 0.);
-    }
-}
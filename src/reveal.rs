@@ -0,0 +1,198 @@
+use bevy::prelude::*;
+use bevy_ecs_ldtk::{prelude::FieldValue, EntityInstance};
+use heron::prelude::*;
+
+use crate::{level::LevelElement, spirit::Spirit, states::States};
+
+pub struct RevealPlugin;
+
+impl Plugin for RevealPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_set(
+            SystemSet::on_update(States::InGame)
+                .with_system(leave_ghost_trail)
+                .with_system(fade_ghosts),
+        )
+        .add_system_to_stage(CoreStage::PostUpdate, tween_reveal);
+    }
+}
+
+/// Whether a spirit has been found, and how far along its reveal (or
+/// dissolve, when `revealed` goes back to false) animation is.
+#[derive(Component)]
+pub struct Reveal {
+    pub revealed: bool,
+    pub progress: f32,
+    /// Seconds a full reveal or dissolve takes.
+    pub duration: f32,
+    /// The spirit's tint from the LDtk `Color` field.
+    pub color: Color,
+    pub scale: f32,
+    pub trail: bool,
+}
+
+impl Reveal {
+    pub fn from_instance(
+        instance: &EntityInstance,
+        color: Color,
+        scale: f32,
+    ) -> Self {
+        let mut reveal = Self {
+            revealed: false,
+            progress: 0.,
+            duration: 0.6,
+            color,
+            scale,
+            trail: true,
+        };
+        for field in instance.field_instances.iter() {
+            match (field.identifier.as_str(), &field.value) {
+                ("RevealTime", FieldValue::Float(Some(time))) => {
+                    reveal.duration = time.max(0.);
+                }
+                ("GhostTrail", FieldValue::Bool(trail)) => {
+                    reveal.trail = *trail;
+                }
+                _ => {}
+            }
+        }
+        reveal
+    }
+
+    /// Shows the spirit straight away, without animating - used when
+    /// restoring a spirit that was found before.
+    pub fn set_instantly(&mut self, revealed: bool) {
+        self.revealed = revealed;
+        self.progress = if revealed { 1. } else { 0. };
+    }
+}
+
+fn ease_out(t: f32) -> f32 {
+    1. - (1. - t).powi(3)
+}
+
+fn tween_reveal(
+    mut spirits: Query<(
+        &mut Reveal,
+        &mut Visibility,
+        &mut TextureAtlasSprite,
+        &mut Transform,
+    )>,
+    time: Res<Time>,
+) {
+    for (mut reveal, mut visibility, mut sprite, mut transform) in
+        spirits.iter_mut()
+    {
+        let step = if reveal.duration > 0. {
+            time.delta_seconds() / reveal.duration
+        } else {
+            1.
+        };
+        reveal.progress = if reveal.revealed {
+            (reveal.progress + step).min(1.)
+        } else {
+            (reveal.progress - step).max(0.)
+        };
+
+        visibility.is_visible = reveal.progress > 0.;
+        let eased = ease_out(reveal.progress);
+
+        // Spirits flash white and grow into place when found, and swell as
+        // they dissolve away
+        let color = reveal.color;
+        let mut tint = if reveal.revealed {
+            Color::rgb(
+                1. + (color.r() - 1.) * eased,
+                1. + (color.g() - 1.) * eased,
+                1. + (color.b() - 1.) * eased,
+            )
+        } else {
+            color
+        };
+        tint.set_a(color.a() * eased);
+        sprite.color = tint;
+
+        let scale = if reveal.revealed {
+            0.6 + 0.4 * eased
+        } else {
+            1.5 - 0.5 * eased
+        };
+        transform.scale = Vec3::ONE * reveal.scale * scale;
+    }
+}
+
+/// A fading copy of a spirit's sprite, left behind as it moves.
+#[derive(Component)]
+struct Ghost {
+    age: f32,
+    alpha: f32,
+}
+
+const GHOST_INTERVAL: f32 = 0.08;
+const GHOST_LIFETIME: f32 = 0.5;
+const GHOST_MIN_SPEED: f32 = 5.;
+
+fn leave_ghost_trail(
+    mut commands: Commands,
+    spirits: Query<
+        (
+            &Reveal,
+            &Transform,
+            &TextureAtlasSprite,
+            &Handle<TextureAtlas>,
+            &Velocity,
+        ),
+        With<Spirit>,
+    >,
+    mut since_last: Local<f32>,
+    time: Res<Time>,
+) {
+    *since_last += time.delta_seconds();
+    if *since_last < GHOST_INTERVAL {
+        return;
+    }
+    *since_last = 0.;
+
+    for (reveal, transform, sprite, atlas, velocity) in spirits.iter() {
+        if !reveal.trail
+            || reveal.progress < 1.
+            || velocity.linear.length() < GHOST_MIN_SPEED
+        {
+            continue;
+        }
+        let alpha = reveal.color.a() * 0.4;
+        let mut color = reveal.color;
+        color.set_a(alpha);
+        commands
+            .spawn_bundle(SpriteSheetBundle {
+                sprite: TextureAtlasSprite {
+                    index: sprite.index,
+                    color,
+                    ..default()
+                },
+                texture_atlas: atlas.clone(),
+                transform: transform
+                    .with_translation(transform.translation - Vec3::Z * 0.1),
+                ..default()
+            })
+            .insert(Ghost { age: 0., alpha })
+            .insert(LevelElement);
+    }
+}
+
+fn fade_ghosts(
+    mut commands: Commands,
+    mut ghosts: Query<(Entity, &mut Ghost, &mut TextureAtlasSprite)>,
+    time: Res<Time>,
+) {
+    for (entity, mut ghost, mut sprite) in ghosts.iter_mut() {
+        ghost.age += time.delta_seconds();
+        if ghost.age >= GHOST_LIFETIME {
+            commands.entity(entity).despawn_recursive();
+        } else {
+            sprite
+                .color
+                .set_a(ghost.alpha * (1. - ghost.age / GHOST_LIFETIME));
+        }
+    }
+}
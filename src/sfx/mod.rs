@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+use bevy_kira_audio::prelude::*;
+
+use crate::{
+    audio::{listener_angle, ConversationAudio, Falloff},
+    level::PortalLockedEvent,
+    loading_state::LoadedAssets,
+    noise::xorshift,
+    player::PlayerControl,
+    sprite_animation::AnimationFrameEvent,
+    states::GameMode,
+};
+
+use self::sound_bank_asset::*;
+
+pub mod sound_bank_asset;
+
+pub struct SfxPlugin;
+
+impl Plugin for SfxPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_asset::<SoundBank>()
+            .init_asset_loader::<SoundBankLoader>()
+            .add_event::<PlaySoundEvent>()
+            .init_resource::<SoundBuses>()
+            .add_system(portal_locked_sounds)
+            .add_system(animation_sounds)
+            .add_system(duck_buses)
+            .add_system_to_stage(CoreStage::PostUpdate, play_sounds);
+    }
+}
+
+/// Plays a sound from the sound bank, from a position in the world if it
+/// has one.
+pub struct PlaySoundEvent {
+    pub sound: String,
+    pub position: Option<Vec3>,
+}
+
+impl PlaySoundEvent {
+    pub fn new(sound: &str) -> Self {
+        Self {
+            sound: sound.to_string(),
+            position: None,
+        }
+    }
+
+    pub fn at(sound: &str, position: Vec3) -> Self {
+        Self {
+            sound: sound.to_string(),
+            position: Some(position),
+        }
+    }
+}
+
+/// Volume settings for each bus, on top of the levels in the sound bank,
+/// and how far each is ducked right now. Buses that aren't listed play at
+/// full volume.
+pub struct SoundBuses {
+    pub levels: HashMap<String, f32>,
+    ducked: HashMap<String, f32>,
+    /// How far the speaking spirit's stem is boosted right now.
+    focus: f32,
+}
+
+impl Default for SoundBuses {
+    fn default() -> Self {
+        Self {
+            levels: HashMap::new(),
+            ducked: HashMap::new(),
+            focus: 1.,
+        }
+    }
+}
+
+impl SoundBuses {
+    /// The bus's volume setting, ignoring ducking.
+    pub fn level(&self, bus: &str) -> f32 {
+        self.levels.get(bus).copied().unwrap_or(1.)
+    }
+
+    pub fn volume(&self, bus: &str) -> f32 {
+        self.level(bus) * self.ducked.get(bus).copied().unwrap_or(1.)
+    }
+
+    pub fn focus(&self) -> f32 {
+        self.focus
+    }
+}
+
+/// Picks variations and pitches, remembering the last file each sound used
+/// so it doesn't play twice in a row.
+struct Variations {
+    seed: u32,
+    last: HashMap<String, usize>,
+}
+
+impl Default for Variations {
+    fn default() -> Self {
+        Self {
+            seed: 0x9E37_79B9,
+            last: HashMap::new(),
+        }
+    }
+}
+
+impl Variations {
+    fn next(&mut self) -> f32 {
+        xorshift(&mut self.seed)
+    }
+
+    fn pick(&mut self, name: &str, count: usize) -> usize {
+        let mut index = ((self.next() * count as f32) as usize).min(count - 1);
+        if count > 1 && self.last.get(name) == Some(&index) {
+            index = (index + 1) % count;
+        }
+        self.last.insert(name.to_string(), index);
+        index
+    }
+}
+
+fn play_sounds(
+    mut events: EventReader<PlaySoundEvent>,
+    mut variations: Local<Variations>,
+    assets: Option<Res<LoadedAssets>>,
+    banks: Res<Assets<SoundBank>>,
+    buses: Res<SoundBuses>,
+    audio: Res<Audio>,
+    asset_server: Res<AssetServer>,
+    listeners: Query<&Transform, With<PlayerControl>>,
+) {
+    let bank = match assets.as_ref().and_then(|a| banks.get(&a.sound_bank)) {
+        Some(bank) => bank,
+        None => {
+            events.clear();
+            return;
+        }
+    };
+    let listener = listeners.get_single().ok();
+
+    for event in events.iter() {
+        let sound = match bank.sounds.get(&event.sound) {
+            Some(sound) => sound,
+            None => {
+                bevy::log::warn!("No sound called {}", &event.sound);
+                continue;
+            }
+        };
+
+        let mut volume = sound.volume
+            * bank.buses.get(&sound.bus).copied().unwrap_or(1.)
+            * buses.volume(&sound.bus);
+        let mut panning = 0.5;
+        if let (Some(position), Some(listener)) = (event.position, listener) {
+            let distance =
+                (position - listener.translation).truncate().length();
+            volume *= Falloff::Linear.volume(distance / sound.range);
+            panning = (listener_angle(listener, position).sin() + 1.) / 2.;
+        }
+        if volume <= 0. {
+            continue;
+        }
+
+        let file =
+            &sound.files[variations.pick(&event.sound, sound.files.len())];
+        let (low, high) = sound.pitch;
+        let pitch = low + (high - low) * variations.next();
+        audio
+            .play(asset_server.load(file))
+            .with_volume(volume as f64)
+            .with_panning(panning as f64)
+            .with_playback_rate(pitch as f64);
+    }
+}
+
+fn portal_locked_sounds(
+    mut locked: EventReader<PortalLockedEvent>,
+    portals: Query<&GlobalTransform>,
+    mut sounds: EventWriter<PlaySoundEvent>,
+) {
+    for PortalLockedEvent(portal) in locked.iter() {
+        sounds.send(match portals.get(*portal) {
+            Ok(transform) => {
+                PlaySoundEvent::at("portal_locked", transform.translation())
+            }
+            Err(_) => PlaySoundEvent::new("portal_locked"),
+        });
+    }
+}
+
+/// Frame events named `sound:<name>` in the animation definitions play that
+/// sound from the animated sprite.
+fn animation_sounds(
+    mut frames: EventReader<AnimationFrameEvent>,
+    sprites: Query<&GlobalTransform>,
+    mut sounds: EventWriter<PlaySoundEvent>,
+) {
+    for frame in frames.iter() {
+        if let Some(sound) = frame.event.strip_prefix("sound:") {
+            if let Ok(transform) = sprites.get(frame.entity) {
+                sounds.send(PlaySoundEvent::at(sound, transform.translation()));
+            }
+        }
+    }
+}
+
+/// Tweens buses towards the bank's ducking levels while a conversation is
+/// open, and back to full once it's over.
+fn duck_buses(
+    mut buses: ResMut<SoundBuses>,
+    assets: Option<Res<LoadedAssets>>,
+    banks: Res<Assets<SoundBank>>,
+    game_mode: Res<State<GameMode>>,
+    conversation: Res<ConversationAudio>,
+    time: Res<Time>,
+) {
+    let ducking = match assets.as_ref().and_then(|a| banks.get(&a.sound_bank)) {
+        Some(bank) => &bank.ducking,
+        None => return,
+    };
+    let ducked = match game_mode.current() {
+        GameMode::Conversation => *conversation == ConversationAudio::Duck,
+        _ => false,
+    };
+    let step = if ducking.fade > 0. {
+        time.delta_seconds() / ducking.fade
+    } else {
+        1.
+    };
+    let towards = |current: f32, target: f32| {
+        current + (target - current).clamp(-step, step)
+    };
+
+    for (bus, level) in ducking.buses.iter() {
+        let target = if ducked { *level } else { 1. };
+        let current = buses.ducked.get(bus).copied().unwrap_or(1.);
+        buses.ducked.insert(bus.clone(), towards(current, target));
+    }
+    let target = if ducked { ducking.focus } else { 1. };
+    buses.focus = towards(buses.focus, target);
+}
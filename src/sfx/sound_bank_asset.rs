@@ -0,0 +1,279 @@
+use std::collections::HashMap;
+
+use bevy::reflect::TypeUuid;
+
+use crate::text_asset::{lines, TextAsset, TextAssetLoader};
+
+#[derive(Debug, Clone)]
+pub struct SoundDefinition {
+    pub bus: String,
+    pub volume: f32,
+    /// Playback rate is picked at random between these, for variety.
+    pub pitch: (f32, f32),
+    /// How far away a positional sound can be heard.
+    pub range: f32,
+    /// Files to pick between each time the sound plays.
+    pub files: Vec<String>,
+}
+
+impl Default for SoundDefinition {
+    fn default() -> Self {
+        Self {
+            bus: "world".to_string(),
+            volume: 1.,
+            pitch: (1., 1.),
+            range: 600.,
+            files: vec![],
+        }
+    }
+}
+
+/// The bus the music stems play on. It doesn't need listing in `[buses]`,
+/// but can be ducked like any other.
+pub const MUSIC_BUS: &str = "music";
+
+/// How conversations duck the other buses, and how much the speaking
+/// spirit's own stem is boosted over them.
+#[derive(Debug, Clone)]
+pub struct Ducking {
+    /// Seconds to duck into and restore from a conversation.
+    pub fade: f32,
+    pub focus: f32,
+    pub buses: HashMap<String, f32>,
+}
+
+impl Default for Ducking {
+    fn default() -> Self {
+        Self {
+            fade: 0.8,
+            focus: 1.,
+            buses: HashMap::new(),
+        }
+    }
+}
+
+/// The one-shot sounds the game can play, and the buses they're mixed on.
+///
+/// ```text
+/// [buses]
+/// ui 0.8
+///
+/// [ducking]
+/// fade 0.8
+/// focus 1.2
+/// music 0.4
+///
+/// [sound ui_click]
+/// bus ui
+/// volume 0.6
+/// pitch 0.95 1.05
+/// files sfx/click_1.ogg sfx/click_2.ogg
+/// ```
+#[derive(Debug, TypeUuid)]
+#[uuid = "e4a7c2d9-3b58-4f61-8d0e-6c1f9b2a5e73"]
+pub struct SoundBank {
+    pub buses: HashMap<String, f32>,
+    pub ducking: Ducking,
+    pub sounds: HashMap<String, SoundDefinition>,
+}
+
+enum Section {
+    Buses,
+    Ducking,
+    Sound(String),
+}
+
+impl TextAsset for SoundBank {
+    const EXTENSIONS: &'static [&'static str] = &["bank"];
+
+    fn parse(source: &str) -> Result<Self, String> {
+        let mut buses = HashMap::new();
+        let mut ducking = Ducking::default();
+        let mut sounds = HashMap::new();
+        let mut section = None;
+
+        for line in lines(source) {
+            let line = line?;
+            let error = |message: String| line.error(message);
+            let parse_number = |word: &str| line.parse::<f32>("number", word);
+
+            if let Some(header) = line.header() {
+                let words: Vec<&str> = header.split_whitespace().collect();
+                section = match words[..] {
+                    ["buses"] => Some(Section::Buses),
+                    ["ducking"] => Some(Section::Ducking),
+                    ["sound", name] => {
+                        sounds.insert(
+                            name.to_string(),
+                            SoundDefinition::default(),
+                        );
+                        Some(Section::Sound(name.to_string()))
+                    }
+                    _ => {
+                        return Err(error(format!(
+                            "Unknown section {}",
+                            header
+                        )))
+                    }
+                };
+                continue;
+            }
+
+            let words = line.words();
+            match &section {
+                Some(Section::Buses) => match words[..] {
+                    [bus, volume] => {
+                        buses.insert(bus.to_string(), parse_number(volume)?);
+                    }
+                    _ => return Err(error("Expected bus volume".to_string())),
+                },
+                Some(Section::Ducking) => match words[..] {
+                    ["fade", fade] => ducking.fade = parse_number(fade)?,
+                    ["focus", focus] => ducking.focus = parse_number(focus)?,
+                    [bus, volume] => {
+                        ducking
+                            .buses
+                            .insert(bus.to_string(), parse_number(volume)?);
+                    }
+                    _ => return Err(error("Expected bus volume".to_string())),
+                },
+                Some(Section::Sound(name)) => {
+                    let sound = sounds.get_mut(name).unwrap();
+                    match words[..] {
+                        ["bus", bus] => sound.bus = bus.to_string(),
+                        ["volume", volume] => {
+                            sound.volume = parse_number(volume)?
+                        }
+                        ["pitch", pitch] => {
+                            let pitch = parse_number(pitch)?;
+                            sound.pitch = (pitch, pitch);
+                        }
+                        ["pitch", low, high] => {
+                            sound.pitch =
+                                (parse_number(low)?, parse_number(high)?);
+                        }
+                        ["range", range] => sound.range = parse_number(range)?,
+                        ["files", ..] => {
+                            sound.files = words[1..]
+                                .iter()
+                                .map(|f| f.to_string())
+                                .collect();
+                        }
+                        _ => {
+                            return Err(error(format!(
+                                "Unknown setting {}",
+                                line.text
+                            )))
+                        }
+                    }
+                }
+                None => {
+                    return Err(error("Line outside of a section".to_string()))
+                }
+            }
+        }
+
+        for (name, sound) in sounds.iter() {
+            if sound.files.is_empty() {
+                return Err(format!("Sound {} has no files", name));
+            }
+            if !buses.contains_key(&sound.bus) {
+                return Err(format!(
+                    "Sound {} uses unknown bus {}",
+                    name, sound.bus
+                ));
+            }
+        }
+
+        for bus in ducking.buses.keys() {
+            if bus != MUSIC_BUS && !buses.contains_key(bus) {
+                return Err(format!("Ducking unknown bus {}", bus));
+            }
+        }
+
+        Ok(Self {
+            buses,
+            ducking,
+            sounds,
+        })
+    }
+}
+
+pub type SoundBankLoader = TextAssetLoader<SoundBank>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buses_ducking_and_sounds_are_parsed() {
+        let source = "\
+[buses]
+ui 0.8
+world 1
+
+[ducking]
+fade 0.5
+focus 1.2
+music 0.4
+world 0.6
+
+[sound ui_click]
+bus ui
+volume 0.6
+pitch 0.95 1.05
+files sfx/click_1.wav sfx/click_2.wav
+
+[sound portal]
+pitch 0.9
+range 300
+files sfx/portal.wav
+";
+        let bank = SoundBank::parse(source).unwrap();
+        assert_eq!(bank.buses["ui"], 0.8);
+        assert_eq!(bank.ducking.fade, 0.5);
+        assert_eq!(bank.ducking.focus, 1.2);
+        assert_eq!(bank.ducking.buses[MUSIC_BUS], 0.4);
+        assert_eq!(bank.ducking.buses["world"], 0.6);
+
+        let click = &bank.sounds["ui_click"];
+        assert_eq!(click.bus, "ui");
+        assert_eq!(click.volume, 0.6);
+        assert_eq!(click.pitch, (0.95, 1.05));
+        assert_eq!(click.files, vec!["sfx/click_1.wav", "sfx/click_2.wav"]);
+
+        let portal = &bank.sounds["portal"];
+        assert_eq!(portal.bus, "world");
+        assert_eq!(portal.volume, 1.);
+        assert_eq!(portal.pitch, (0.9, 0.9));
+        assert_eq!(portal.range, 300.);
+    }
+
+    #[test]
+    fn unknown_settings_are_errors() {
+        let error = SoundBank::parse("[sound a]\nloudness 2");
+        assert_eq!(error.unwrap_err(), "Line 2: Unknown setting loudness 2");
+        let error = SoundBank::parse("[voices]");
+        assert_eq!(error.unwrap_err(), "Line 1: Unknown section voices");
+        let error = SoundBank::parse("[sound a]\nbus ui\nfiles a.wav");
+        assert_eq!(error.unwrap_err(), "Sound a uses unknown bus ui");
+        let error = SoundBank::parse("[ducking]\nvoice 0.5");
+        assert_eq!(error.unwrap_err(), "Ducking unknown bus voice");
+    }
+
+    #[test]
+    fn bad_numbers_are_errors() {
+        let error = SoundBank::parse("[buses]\nui loud");
+        assert_eq!(error.unwrap_err(), "Line 2: Invalid number loud");
+        let error = SoundBank::parse("[sound a]\npitch 1 high");
+        assert_eq!(error.unwrap_err(), "Line 2: Invalid number high");
+    }
+
+    #[test]
+    fn duplicate_sounds_are_errors() {
+        let error = SoundBank::parse(
+            "[buses]\nworld 1\n[sound a]\nfiles a.wav\n[sound a]",
+        );
+        assert_eq!(error.unwrap_err(), "Line 5: Duplicate section [sound a]");
+    }
+}
@@ -9,13 +9,25 @@ use heron::{prelude::*, rapier_plugin::PhysicsWorld};
 use leafwing_input_manager::prelude::ActionState;
 
 use crate::{
-    audio::AudioEmitter,
+    activation::ElementGroups,
+    audio::{AudioEmitter, SpatialAudio},
     interactive_narrative::SetCurrentKnotEvent,
-    level::{ActiveElement, DeactivateElement, LevelElement, NamedElement},
+    level::{
+        is_building_level, ActiveElement, DeactivateElement, LevelElement,
+        LevelLookup, NamedElement, OwningLevel,
+    },
     loading_state::LoadedAssets,
     physics::GameCollisionLayers,
     player::{Action, PlayerControl},
+    reveal::Reveal,
+    sfx::PlaySoundEvent,
+    sprite_animation::{
+        animation_asset::{ClipMode, SpriteClip},
+        SpriteAnimator,
+    },
     states::{GameMode, States},
+    steering::{spirit_steering, SpiritState},
+    world_state::WorldState,
 };
 
 pub struct SpiritPlugin;
@@ -23,41 +35,146 @@ pub struct SpiritPlugin;
 impl Plugin for SpiritPlugin {
     fn build(&self, app: &mut App) {
         app.init_resource::<AwaitingEmitters>()
+            .init_resource::<SightlineBudget>()
+            .init_resource::<SpiritProximity>()
+            .add_event::<FailedInteractEvent>()
             .add_system_set(
                 SystemSet::on_update(States::InGame)
-                    .with_system(spirit_avoid_player)
-                    .with_system(spirit_surrounder)
                     .with_system(determine_sightline)
-                    .with_system(animate_spirits),
+                    .with_system(measure_spirit_proximity),
             )
             .add_system_to_stage(CoreStage::PostUpdate, deactivate_elements)
             .add_system_set(
                 SystemSet::on_update(GameMode::Exploration)
                     .with_system(trigger_knot),
             )
+            .add_system_set(
+                SystemSet::new()
+                    .with_run_criteria(is_building_level)
+                    .with_system(spawn_spirit),
+            )
             .add_system_set(
                 SystemSet::on_update(States::LoadingLevel)
-                    .with_system(spawn_spirit)
                     .with_system(spirits_ready),
             );
     }
 }
 
 #[derive(Component)]
-pub struct Spirit(f32);
+pub struct Spirit(pub f32);
 
 #[derive(Component)]
-pub struct SpiritAvoidPlayer;
+pub struct TargetKnot(pub String);
 
+/// How close the player has to be to a `TargetKnot` spirit to interact
+/// with it.
 #[derive(Component)]
-pub struct SpiritSurrounder(f32, f32);
+pub struct InteractRadius(pub f32);
+
+impl InteractRadius {
+    fn from_instance(instance: &EntityInstance) -> Self {
+        let mut radius = 100.;
+        for field in instance.field_instances.iter() {
+            if let ("InteractRadius", FieldValue::Float(Some(value))) =
+                (field.identifier.as_str(), &field.value)
+            {
+                radius = *value;
+            }
+        }
+        Self(radius)
+    }
+}
 
-#[derive(Component)]
-pub struct TargetKnot(String);
+/// How close the player is to the nearest spirit they could interact with,
+/// from `0` (out of range) to `1` (right next to it).
+#[derive(Default)]
+pub struct SpiritProximity {
+    pub closeness: f32,
+    pub nearest: Option<Entity>,
+}
+
+const PROXIMITY_RANGE: f32 = 400.;
+
+/// Sent when the player presses interact without a spirit in reach.
+pub struct FailedInteractEvent;
 
 #[derive(Component)]
 #[component(storage = "SparseSet")]
-struct CanSeePlayer;
+pub struct CanSeePlayer;
+
+/// How a spirit notices the player. `field_of_view` is the full cone angle
+/// in radians around the direction the spirit is moving.
+#[derive(Component)]
+pub struct Sightline {
+    pub range: f32,
+    pub field_of_view: f32,
+    pub notice_time: f32,
+    pub forget_time: f32,
+}
+
+impl Sightline {
+    fn from_instance(instance: &EntityInstance) -> Self {
+        let mut sightline = Self {
+            range: 400.,
+            field_of_view: 2. * PI,
+            notice_time: 0.2,
+            forget_time: 1.,
+        };
+        for field in instance.field_instances.iter() {
+            match (field.identifier.as_str(), &field.value) {
+                ("SightRange", FieldValue::Float(Some(range))) => {
+                    sightline.range = *range;
+                }
+                ("FieldOfView", FieldValue::Float(Some(degrees))) => {
+                    sightline.field_of_view = degrees * PI / 180.;
+                }
+                ("NoticeTime", FieldValue::Float(Some(time))) => {
+                    sightline.notice_time = *time;
+                }
+                ("ForgetTime", FieldValue::Float(Some(time))) => {
+                    sightline.forget_time = *time;
+                }
+                _ => {}
+            }
+        }
+        sightline
+    }
+}
+
+#[derive(Component)]
+struct Awareness {
+    in_view: bool,
+    seen_for: f32,
+    unseen_for: f32,
+    facing: Vec3,
+}
+
+impl Default for Awareness {
+    fn default() -> Self {
+        Self {
+            in_view: false,
+            seen_for: 0.,
+            unseen_for: 0.,
+            facing: Vec3::Y,
+        }
+    }
+}
+
+/// Caps how many sightline ray casts happen per frame. Spirits take turns,
+/// and keep their last result until it's their turn again.
+pub struct SightlineBudget {
+    pub rays_per_frame: usize,
+    next: usize,
+}
+
+impl Default for SightlineBudget {
+    fn default() -> Self {
+        Self {
+            rays_per_frame: 8,
+            next: 0,
+        }
+    }
+}
 
 pub struct AwaitingEmitters {
     pub emitters: Vec<Handle<AudioSource>>,
@@ -77,12 +194,6 @@ pub struct CharacterAtlas {
     pub atlas: Handle<TextureAtlas>,
 }
 
-#[derive(Component)]
-pub struct SpiritAnimationIndices {
-    pub start: usize,
-    pub len: usize,
-}
-
 fn spirits_ready(
     mut awaiting_emitters: ResMut<AwaitingEmitters>,
     mut app_state: ResMut<State<States>>,
@@ -123,7 +234,12 @@ fn spawn_spirit(
     asset_server: Res<AssetServer>,
     texture_atlas: Option<Res<CharacterAtlas>>,
     mut texture_atlases: ResMut<Assets<TextureAtlas>>,
-    entities: Query<(&EntityInstance, &Transform), Added<EntityInstance>>,
+    entities: Query<
+        (Entity, &EntityInstance, &Transform),
+        Added<EntityInstance>,
+    >,
+    levels: LevelLookup,
+    app_state: Res<State<States>>,
 ) {
     let atlas_handle = match texture_atlas {
         Some(atlas) => atlas.atlas.clone(),
@@ -144,46 +260,33 @@ fn spawn_spirit(
 
     let mut emitters: Vec<Handle<AudioSource>> = vec![];
     let mut found_entites = false;
-    for (instance, transform) in entities.iter() {
+    for (instance_entity, instance, transform) in entities.iter() {
         found_entites = true;
+        let (level, transform) = match levels.locate(instance_entity) {
+            Some((level, transform)) => (Some(level), transform),
+            None => (None, *transform),
+        };
         let spawning = match instance.identifier.as_str() {
             "StationarySpirit" => {
                 Some(commands.spawn().insert(RigidBody::Sensor).id())
             }
-            "RandomWalkSpirit" => Some(
-                commands
-                    .spawn()
-                    .insert(RigidBody::Dynamic)
-                    .insert(SpiritAvoidPlayer)
-                    .id(),
-            ),
-            "CirclingSpirit" => {
-                let mut angle = 10f32;
-                let mut distance = 120f32;
-
-                for field in instance.field_instances.iter() {
-                    match field.identifier.as_str() {
-                        "AngularSpeed" => {
-                            if let FieldValue::Float(Some(speed)) = field.value
-                            {
-                                angle = speed;
-                            }
-                        }
-                        "TargetDistance" => {
-                            if let FieldValue::Float(Some(d)) = field.value {
-                                distance = d;
-                            }
-                        }
-                        _ => {}
-                    }
-                }
-
-                Some(
-                    commands
-                        .spawn()
-                        .insert(RigidBody::Dynamic)
-                        .insert(SpiritSurrounder(angle * PI / 180., distance))
-                        .id(),
+            "RandomWalkSpirit" | "CirclingSpirit" | "PathSpirit" => {
+                let path = levels
+                    .grid_size(instance_entity)
+                    .map(|grid| path_points(instance, &transform, grid))
+                    .unwrap_or_default();
+                spirit_steering(instance, path).map(
+                    |(steering, mind, path_start)| {
+                        let entity = commands.spawn().id();
+                        commands
+                            .entity(entity)
+                            .insert(RigidBody::Dynamic)
+                            .insert(SpiritState::Idle)
+                            .insert(steering.seeded(entity))
+                            .insert(mind)
+                            .insert(path_start);
+                        entity
+                    },
                 )
             }
             _ => None,
@@ -193,12 +296,13 @@ fn spawn_spirit(
             let (
                 max_speed,
                 audio,
-                _color,
+                color,
                 knot,
                 animation_start,
                 animation_end,
                 active,
                 id,
+                groups,
             ) = {
                 let mut max_speed = 9.5f32;
                 let mut audio = None;
@@ -208,6 +312,7 @@ fn spawn_spirit(
                 let mut animation_end = 0usize;
                 let mut active = false;
                 let mut id = None;
+                let mut groups = vec![];
 
                 for field in instance.field_instances.iter() {
                     match field.identifier.as_str() {
@@ -265,6 +370,12 @@ fn spawn_spirit(
                                 active = *start_enabled;
                             }
                         }
+                        "Groups" => {
+                            if let FieldValue::Strings(names) = &field.value {
+                                groups =
+                                    names.iter().flatten().cloned().collect();
+                            }
+                        }
                         _ => {}
                     }
                 }
@@ -278,6 +389,7 @@ fn spawn_spirit(
                     animation_end,
                     active,
                     id,
+                    groups,
                 )
             };
 
@@ -293,14 +405,24 @@ fn spawn_spirit(
                     ..default()
                 })
                 .insert(LevelElement)
-                .insert(SpiritAnimationIndices {
-                    len: animation_end
-                        .checked_sub(animation_start)
-                        .unwrap_or(1)
-                        + 1,
-                    start: animation_start,
-                })
+                .insert(
+                    SpriteAnimator::new("spirit", "idle")
+                        .with_offset(animation_start)
+                        .with_clip(
+                            "idle",
+                            SpriteClip::new(
+                                (0..=animation_end
+                                    .saturating_sub(animation_start))
+                                    .collect(),
+                                5.,
+                                ClipMode::Loop,
+                            ),
+                        ),
+                )
                 .insert(Spirit(max_speed))
+                .insert(Reveal::from_instance(instance, color, 0.5))
+                .insert(Sightline::from_instance(instance))
+                .insert(Awareness::default())
                 .insert(CollisionShape::Sphere { radius: 16. })
                 .insert(PhysicMaterial {
                     restitution: 0.9,
@@ -315,10 +437,14 @@ fn spawn_spirit(
                 );
 
             if let Some((audio, file)) = audio {
-                spawning.insert(AudioEmitter(audio, file));
+                spawning
+                    .insert(AudioEmitter(audio, file))
+                    .insert(SpatialAudio::from_instance(instance));
             }
             if let Some(knot) = knot {
-                spawning.insert(TargetKnot(knot.clone()));
+                spawning
+                    .insert(TargetKnot(knot.clone()))
+                    .insert(InteractRadius::from_instance(instance));
             }
 
             if active {
@@ -327,28 +453,95 @@ fn spawn_spirit(
             if let Some(id) = id {
                 spawning.insert(NamedElement(id));
             }
+            if let Some(level) = level {
+                spawning.insert(OwningLevel(level));
+            }
+            if !groups.is_empty() {
+                spawning.insert(ElementGroups(groups));
+            }
         }
     }
 
-    if found_entites {
+    if found_entites && *app_state.current() == States::LoadingLevel {
         awaiting_emitters.emitters = emitters;
         awaiting_emitters.is_set = true;
     }
 }
 
+/// World positions of an entity's `Path` points, starting from where the
+/// entity itself was placed.
+fn path_points(
+    instance: &EntityInstance,
+    transform: &Transform,
+    grid_size: f32,
+) -> Vec<Vec3> {
+    let mut points = vec![transform.translation];
+    for field in instance.field_instances.iter() {
+        if let ("Path", FieldValue::Points(cells)) =
+            (field.identifier.as_str(), &field.value)
+        {
+            for cell in cells.iter().flatten() {
+                // LDtk grid coordinates grow downwards
+                let offset = (*cell - instance.grid).as_vec2() * grid_size;
+                points.push(
+                    transform.translation + Vec3::new(offset.x, -offset.y, 0.),
+                );
+            }
+        }
+    }
+    points
+}
+
 fn determine_sightline(
     mut commands: Commands,
-    spirits: Query<(Entity, &Transform), (With<Spirit>, With<ActiveElement>)>,
+    mut spirits: Query<
+        (
+            Entity,
+            &Transform,
+            &Velocity,
+            &Sightline,
+            &mut Awareness,
+            Option<&CanSeePlayer>,
+        ),
+        (With<Spirit>, With<ActiveElement>),
+    >,
     players: Query<(Entity, &Transform), With<PlayerControl>>,
     physics_world: PhysicsWorld,
+    mut budget: ResMut<SightlineBudget>,
+    time: Res<Time>,
 ) {
-    bevy::log::debug!("Looking for player");
-    let target = players.get_single();
-    if let Ok((player, player_transform)) = target {
-        let target = player_transform.translation;
-        bevy::log::debug!("Got player position {:?}", &target);
+    let (player, target) = match players.get_single() {
+        Ok((player, transform)) => (player, transform.translation),
+        Err(_) => return,
+    };
+    let delta = time.delta_seconds();
+    let count = spirits.iter().count();
+    if count == 0 {
+        return;
+    }
+    let first = budget.next % count;
+    budget.next = (first + budget.rays_per_frame) % count;
+
+    for (
+        index,
+        (entity, transform, velocity, sightline, mut awareness, seen),
+    ) in spirits.iter_mut().enumerate()
+    {
+        let offset = (target - transform.translation).truncate().extend(0.);
+        if velocity.linear.truncate().length() > 1. {
+            awareness.facing =
+                velocity.linear.truncate().extend(0.).normalize();
+        }
 
-        for (entity, transform) in spirits.iter() {
+        // Range and view cone are cheap, so they're checked every frame
+        let in_cone = offset.length() <= sightline.range
+            && (sightline.field_of_view >= 2. * PI
+                || awareness.facing.angle_between(offset)
+                    <= sightline.field_of_view / 2.);
+
+        if !in_cone {
+            awareness.in_view = false;
+        } else if (index + count - first) % count < budget.rays_per_frame {
             bevy::log::debug!("Checking from {:?}", &transform.translation);
             let result = physics_world.ray_cast_with_filter(
                 transform.translation,
@@ -361,125 +554,74 @@ fn determine_sightline(
                     ]),
                 |_entity| true,
             );
-            if let Some(collision_info) = result {
-                if collision_info.entity == player {
-                    bevy::log::debug!("Player found");
-                    commands.entity(entity).insert(CanSeePlayer);
-                } else {
-                    bevy::log::debug!("Player not found");
-                    commands.entity(entity).remove::<CanSeePlayer>();
-                }
-            } else {
-                bevy::log::debug!("No collision");
+            awareness.in_view =
+                result.map_or(false, |info| info.entity == player);
+        }
+
+        if awareness.in_view {
+            awareness.seen_for += delta;
+            awareness.unseen_for = 0.;
+            if seen.is_none() && awareness.seen_for >= sightline.notice_time {
+                bevy::log::debug!("Player found");
+                commands.entity(entity).insert(CanSeePlayer);
+            }
+        } else {
+            awareness.unseen_for += delta;
+            awareness.seen_for = 0.;
+            if seen.is_some() && awareness.unseen_for >= sightline.forget_time
+            {
+                bevy::log::debug!("Player lost");
+                commands.entity(entity).remove::<CanSeePlayer>();
             }
         }
     }
 }
 
-fn spirit_avoid_player(
-    mut spirits: Query<
-        (&Transform, &Spirit, &mut Velocity),
-        (
-            With<SpiritAvoidPlayer>,
-            Without<PlayerControl>,
-            With<CanSeePlayer>,
-            With<ActiveElement>,
-        ),
+fn measure_spirit_proximity(
+    spirits: Query<
+        (Entity, &Transform, &Reveal),
+        (With<Spirit>, With<TargetKnot>, With<ActiveElement>),
     >,
     players: Query<&Transform, With<PlayerControl>>,
-    time: Res<Time>,
-    window: Res<Windows>,
+    mut proximity: ResMut<SpiritProximity>,
 ) {
-    let target = players.get_single();
-    let speed = 95f32;
-    let delta = time.delta().as_secs_f32();
-    let _bounds = if let Some(window) = window.get_primary() {
-        (window.width() / 2., window.height() / 2.)
-    } else {
-        (500., 500.)
+    let player = match players.get_single() {
+        Ok(player) => player.translation,
+        Err(_) => return,
     };
-
-    let player_position = if let Ok(player) = target {
-        player.translation
-    } else {
-        Vec3::ZERO
-    };
-
-    for (transform, spirit, mut velocity_component) in spirits.iter_mut() {
-        let direction = transform.translation - player_position;
-        if direction.length() > 300. {
-            return;
+    // Spirits that have already been found have nothing left to hint at
+    let nearest = spirits
+        .iter()
+        .filter(|(_, _, reveal)| !reveal.revealed)
+        .map(|(entity, transform, _)| {
+            (entity, (transform.translation - player).truncate().length())
+        })
+        .min_by(|(_, a), (_, b)| a.total_cmp(b));
+
+    match nearest {
+        Some((entity, distance)) => {
+            proximity.nearest = Some(entity);
+            proximity.closeness =
+                (1. - distance / PROXIMITY_RANGE).clamp(0., 1.);
         }
-        let direction = direction.normalize();
-
-        let mut velocity =
-            velocity_component.linear + direction * delta * speed;
-
-        if velocity.length() > spirit.0 {
-            velocity = velocity.normalize() * (spirit.0 - 1.);
+        None => {
+            proximity.nearest = None;
+            proximity.closeness = 0.;
         }
-
-        velocity_component.linear = velocity;
     }
 }
 
-fn spirit_surrounder(
+fn trigger_knot(
     mut spirits: Query<
-        (&Transform, &Spirit, &SpiritSurrounder, &mut Velocity),
         (
-            Without<PlayerControl>,
-            With<CanSeePlayer>,
-            With<ActiveElement>,
+            &Transform,
+            &TargetKnot,
+            &InteractRadius,
+            &mut Reveal,
+            &mut SpriteAnimator,
+            Option<&NamedElement>,
+            Option<&OwningLevel>,
         ),
-    >,
-    players: Query<&Transform, With<PlayerControl>>,
-    time: Res<Time>,
-    window: Res<Windows>,
-) {
-    let target = players.get_single();
-    let speed = 95f32;
-    let delta = time.delta().as_secs_f32();
-    let elapsed = time.time_since_startup().as_secs_f32();
-    let _bounds = if let Some(window) = window.get_primary() {
-        (window.width() / 2., window.height() / 2.)
-    } else {
-        (500., 500.)
-    };
-
-    let player_position = if let Ok(player) = target {
-        player.translation
-    } else {
-        Vec3::ZERO
-    };
-
-    for (
-        transform,
-        spirit,
-        SpiritSurrounder(angle, target_distance),
-        mut velocity_component,
-    ) in spirits.iter_mut()
-    {
-        let target_position = player_position
-            + *target_distance
-                * (Quat::from_rotation_z(angle * elapsed).mul_vec3(Vec3::Y));
-
-        let direction = target_position - transform.translation;
-        let direction = direction.normalize();
-
-        let mut velocity =
-            velocity_component.linear + direction * delta * speed;
-
-        if velocity.length() > spirit.0 {
-            velocity = velocity.normalize() * (spirit.0 - 1.);
-        }
-
-        velocity_component.linear = velocity;
-    }
-}
-
-fn trigger_knot(
-    mut spirits: Query<
-        (&Transform, &TargetKnot, &mut Visibility),
         (
             With<Spirit>,
             Without<PlayerControl>,
@@ -489,16 +631,45 @@ fn trigger_knot(
     >,
     players: Query<(&Transform, &ActionState<Action>), With<PlayerControl>>,
     mut event_writer: EventWriter<SetCurrentKnotEvent>,
+    mut failed: EventWriter<FailedInteractEvent>,
+    mut sounds: EventWriter<PlaySoundEvent>,
+    mut world_state: ResMut<WorldState>,
 ) {
     let mut target_knot = None;
     for (player, action) in players.iter() {
         if action.pressed(Action::Interact) {
-            for (spirit, knot, mut visibility) in spirits.iter_mut() {
-                if (player.translation - spirit.translation).length() < 100. {
+            for (
+                spirit,
+                knot,
+                radius,
+                mut reveal,
+                mut animator,
+                name,
+                level,
+            ) in spirits.iter_mut()
+            {
+                if (player.translation - spirit.translation).length()
+                    < radius.0
+                {
                     target_knot = Some(knot.0.clone());
-                    visibility.is_visible = true;
+                    if !reveal.revealed {
+                        animator.restart("reveal");
+                        sounds.send(PlaySoundEvent::at(
+                            "spirit_reveal",
+                            spirit.translation,
+                        ));
+                    }
+                    reveal.revealed = true;
+                    if let (Some(name), Some(level)) = (name, level) {
+                        world_state.record_visible(&level.0, &name.0, true);
+                        world_state.record_triggered(&level.0, &name.0);
+                    }
                 }
             }
+            if target_knot.is_none() && action.just_pressed(Action::Interact)
+            {
+                failed.send(FailedInteractEvent);
+            }
         }
     }
 
@@ -507,27 +678,17 @@ fn trigger_knot(
     }
 }
 
-fn animate_spirits(
-    mut spirits: Query<
-        (&mut TextureAtlasSprite, &SpiritAnimationIndices),
-        (With<Spirit>, With<ActiveElement>),
-    >,
-    time: Res<Time>,
-) {
-    let time = (time.seconds_since_startup() * 5.) as usize;
-    for (mut sprite, animation) in spirits.iter_mut() {
-        let current_index = animation.start + (time % animation.len);
-        sprite.index = current_index;
-    }
-}
-
 fn deactivate_elements(
     mut spirits: Query<
-        &mut Visibility,
+        (&mut Reveal, Option<&NamedElement>, Option<&OwningLevel>),
         (With<Spirit>, With<DeactivateElement>),
     >,
+    mut world_state: ResMut<WorldState>,
 ) {
-    for mut visibility in spirits.iter_mut() {
-        visibility.is_visible = false;
+    for (mut reveal, name, level) in spirits.iter_mut() {
+        reveal.revealed = false;
+        if let (Some(name), Some(level)) = (name, level) {
+            world_state.record_visible(&level.0, &name.0, false);
+        }
     }
 }
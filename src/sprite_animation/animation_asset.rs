@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+
+use bevy::reflect::TypeUuid;
+
+use crate::text_asset::{lines, TextAsset, TextAssetLoader};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClipMode {
+    Loop,
+    Once,
+    PingPong,
+}
+
+#[derive(Debug, Clone)]
+pub struct SpriteClip {
+    /// Atlas indices, in the order they are played.
+    pub frames: Vec<usize>,
+    pub fps: f32,
+    pub mode: ClipMode,
+    /// Clip to switch to when a `Once` clip finishes.
+    pub next: Option<String>,
+    /// Events sent when the clip reaches a frame, counted from the start of
+    /// the clip.
+    pub events: Vec<(usize, String)>,
+}
+
+impl SpriteClip {
+    pub fn new(frames: Vec<usize>, fps: f32, mode: ClipMode) -> Self {
+        Self {
+            frames,
+            fps,
+            mode,
+            next: None,
+            events: vec![],
+        }
+    }
+}
+
+/// Named clips for the sprites in an atlas, grouped into sets - usually one
+/// per character.
+///
+/// The definition file is plain text:
+///
+/// ```text
+/// # Comment
+/// [player]
+/// idle 3-7 fps=0.5
+/// walk 19-22 fps=5 on=1:footstep,3:footstep
+/// reveal 0,1,2,1 fps=12 mode=once next=idle
+/// ```
+#[derive(Debug, TypeUuid)]
+#[uuid = "5d3c0a4e-8f41-4c55-9a52-0b6f8f7d2e19"]
+pub struct SpriteAnimations {
+    pub sets: HashMap<String, HashMap<String, SpriteClip>>,
+}
+
+impl SpriteAnimations {
+    pub fn clip(&self, set: &str, name: &str) -> Option<&SpriteClip> {
+        self.sets.get(set).and_then(|clips| clips.get(name))
+    }
+}
+
+impl TextAsset for SpriteAnimations {
+    const EXTENSIONS: &'static [&'static str] = &["anim"];
+
+    fn parse(source: &str) -> Result<Self, String> {
+        let mut sets: HashMap<String, HashMap<String, SpriteClip>> =
+            HashMap::new();
+        let mut current = None;
+
+        for line in lines(source) {
+            let line = line?;
+            if let Some(set) = line.header() {
+                sets.insert(set.to_string(), HashMap::new());
+                current = Some(set);
+                continue;
+            }
+
+            let set =
+                current.ok_or_else(|| line.error("Clip outside of a [set]"))?;
+            let mut words = line.words().into_iter();
+            let name = words.next().unwrap_or_default().to_string();
+            let frames = parse_frames(words.next().unwrap_or_default())
+                .map_err(|message| line.error(message))?;
+            let mut clip = SpriteClip::new(frames, 5., ClipMode::Loop);
+
+            for option in words {
+                let (key, value) = option.split_once('=').ok_or_else(|| {
+                    line.error(format!("Expected key=value, found {}", option))
+                })?;
+                match key {
+                    "fps" => clip.fps = line.parse("fps", value)?,
+                    "mode" => {
+                        clip.mode = match value {
+                            "loop" => ClipMode::Loop,
+                            "once" => ClipMode::Once,
+                            "pingpong" => ClipMode::PingPong,
+                            _ => {
+                                return Err(line
+                                    .error(format!("Unknown mode {}", value)))
+                            }
+                        };
+                    }
+                    "next" => clip.next = Some(value.to_string()),
+                    "on" => {
+                        for event in value.split(',') {
+                            let (frame, name) =
+                                event.split_once(':').ok_or_else(|| {
+                                    line.error(format!(
+                                        "Invalid event {}",
+                                        event
+                                    ))
+                                })?;
+                            let frame = line.parse("frame", frame)?;
+                            clip.events.push((frame, name.to_string()));
+                        }
+                    }
+                    _ => {
+                        return Err(
+                            line.error(format!("Unknown option {}", key))
+                        )
+                    }
+                }
+            }
+
+            sets.get_mut(set).unwrap().insert(name, clip);
+        }
+
+        Ok(Self { sets })
+    }
+}
+
+/// Parses a frame list like `3-7` or `0,1,2,1`.
+fn parse_frames(source: &str) -> Result<Vec<usize>, String> {
+    let mut frames = vec![];
+    for part in source.split(',') {
+        let invalid = || format!("Invalid frames {}", source);
+        match part.split_once('-') {
+            Some((first, last)) => {
+                let first: usize = first.parse().map_err(|_| invalid())?;
+                let last: usize = last.parse().map_err(|_| invalid())?;
+                if last >= first {
+                    frames.extend(first..=last);
+                } else {
+                    frames.extend((last..=first).rev());
+                }
+            }
+            None => frames.push(part.parse().map_err(|_| invalid())?),
+        }
+    }
+    Ok(frames)
+}
+
+pub type SpriteAnimationsLoader = TextAssetLoader<SpriteAnimations>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clips_are_parsed() {
+        let source = "\
+# Comment
+[player]
+idle 3-7 fps=0.5
+walk 22-19 fps=5 on=1:footstep,3:footstep
+reveal 0,1,2,1 fps=12 mode=once next=idle
+";
+        let animations = SpriteAnimations::parse(source).unwrap();
+        let idle = animations.clip("player", "idle").unwrap();
+        assert_eq!(idle.frames, vec![3, 4, 5, 6, 7]);
+        assert_eq!(idle.fps, 0.5);
+        assert_eq!(idle.mode, ClipMode::Loop);
+
+        let walk = animations.clip("player", "walk").unwrap();
+        assert_eq!(walk.frames, vec![22, 21, 20, 19]);
+        assert_eq!(
+            walk.events,
+            vec![(1, "footstep".to_string()), (3, "footstep".to_string())]
+        );
+
+        let reveal = animations.clip("player", "reveal").unwrap();
+        assert_eq!(reveal.frames, vec![0, 1, 2, 1]);
+        assert_eq!(reveal.mode, ClipMode::Once);
+        assert_eq!(reveal.next.as_deref(), Some("idle"));
+        assert!(animations.clip("cass", "idle").is_none());
+    }
+
+    #[test]
+    fn unknown_options_are_errors() {
+        let error = SpriteAnimations::parse("[player]\nidle 3-7 speed=2");
+        assert_eq!(error.unwrap_err(), "Line 2: Unknown option speed");
+        let error = SpriteAnimations::parse("[player]\nidle 3-7 mode=bounce");
+        assert_eq!(error.unwrap_err(), "Line 2: Unknown mode bounce");
+    }
+
+    #[test]
+    fn bad_numbers_are_errors() {
+        let error = SpriteAnimations::parse("[player]\nidle 3-7 fps=fast");
+        assert_eq!(error.unwrap_err(), "Line 2: Invalid fps fast");
+        let error = SpriteAnimations::parse("[player]\nidle 3-x");
+        assert_eq!(error.unwrap_err(), "Line 2: Invalid frames 3-x");
+        let error = SpriteAnimations::parse("[player]\nidle 3 on=a:step");
+        assert_eq!(error.unwrap_err(), "Line 2: Invalid frame a");
+    }
+
+    #[test]
+    fn clips_need_a_set() {
+        let error = SpriteAnimations::parse("idle 3-7");
+        assert_eq!(error.unwrap_err(), "Line 1: Clip outside of a [set]");
+    }
+
+    #[test]
+    fn duplicate_sets_are_errors() {
+        let error = SpriteAnimations::parse("[player]\nidle 3\n[player]");
+        assert_eq!(error.unwrap_err(), "Line 3: Duplicate section [player]");
+    }
+}
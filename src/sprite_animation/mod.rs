@@ -0,0 +1,259 @@
+use std::{collections::HashMap, f32::consts::TAU};
+
+use bevy::prelude::*;
+
+use crate::loading_state::LoadedAssets;
+
+use self::animation_asset::*;
+
+pub mod animation_asset;
+
+pub struct SpriteAnimationPlugin;
+
+impl Plugin for SpriteAnimationPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_asset::<SpriteAnimations>()
+            .init_asset_loader::<SpriteAnimationsLoader>()
+            .add_event::<AnimationFrameEvent>()
+            .add_system(animate_sprites);
+    }
+}
+
+/// Sent when a sprite reaches a frame that has an event attached in the
+/// animation definition.
+pub struct AnimationFrameEvent {
+    pub entity: Entity,
+    pub clip: String,
+    pub event: String,
+}
+
+#[derive(Default)]
+struct Playback {
+    position: usize,
+    elapsed: f32,
+    backwards: bool,
+    finished: bool,
+    started: bool,
+}
+
+impl Playback {
+    /// Moves through the clip, collecting the positions of the frames it
+    /// lands on.
+    fn advance(&mut self, clip: &SpriteClip, delta: f32) -> Vec<usize> {
+        let mut entered = vec![];
+        if !self.started {
+            self.started = true;
+            entered.push(self.position);
+        }
+
+        self.elapsed += delta * clip.fps;
+        let last = clip.frames.len() - 1;
+        // Directional variants of a clip can have different lengths
+        self.position = self.position.min(last);
+        while self.elapsed >= 1. && !self.finished {
+            self.elapsed -= 1.;
+            match clip.mode {
+                ClipMode::Loop => {
+                    self.position = (self.position + 1) % (last + 1)
+                }
+                ClipMode::Once => {
+                    if self.position < last {
+                        self.position += 1;
+                    } else {
+                        self.finished = true;
+                        continue;
+                    }
+                }
+                ClipMode::PingPong => {
+                    if last == 0 {
+                        continue;
+                    }
+                    if self.position == last {
+                        self.backwards = true;
+                    } else if self.position == 0 {
+                        self.backwards = false;
+                    }
+                    if self.backwards {
+                        self.position -= 1;
+                    } else {
+                        self.position += 1;
+                    }
+                }
+            }
+            entered.push(self.position);
+        }
+        entered
+    }
+}
+
+/// Which way a sprite is facing, in radians anticlockwise from east.
+#[derive(Debug, Clone, Copy)]
+pub struct Facing(pub f32);
+
+const DIRECTIONS: [&str; 8] = ["e", "ne", "n", "nw", "w", "sw", "s", "se"];
+
+impl Facing {
+    fn sector(self, count: usize) -> usize {
+        let step = TAU / count as f32;
+        ((self.0.rem_euclid(TAU) + step / 2.) / step) as usize % count
+    }
+
+    /// Clip suffixes to look for, from 8 directions then 4, and whether to
+    /// flip the sprite. Westward clips fall back to the eastward ones,
+    /// mirrored.
+    fn candidates(self) -> Vec<(&'static str, bool)> {
+        let eight = DIRECTIONS[self.sector(8)];
+        let four = DIRECTIONS[self.sector(4) * 2];
+        let mut candidates = vec![(eight, false), (four, false)];
+        for direction in [eight, four] {
+            let mirrored = match direction {
+                "w" => "e",
+                "nw" => "ne",
+                "sw" => "se",
+                _ => continue,
+            };
+            candidates.push((mirrored, true));
+        }
+        candidates
+    }
+}
+
+/// Plays named clips from the character animation definitions on a
+/// `TextureAtlasSprite`. Each sprite keeps its own clock, so switching clips
+/// always starts from the first frame.
+#[derive(Component)]
+pub struct SpriteAnimator {
+    /// Which set of clips in the definition file to use.
+    pub set: String,
+    /// Added to every frame, for sprites that share clips but live in
+    /// different parts of the atlas.
+    pub offset: usize,
+    pub speed: f32,
+    /// When set, clips named like `walk_ne` or `walk_e` are used in place of
+    /// `walk` for that direction.
+    pub facing: Option<Facing>,
+    clip: String,
+    clips: HashMap<String, SpriteClip>,
+    playback: Playback,
+}
+
+impl SpriteAnimator {
+    pub fn new(set: &str, clip: &str) -> Self {
+        Self {
+            set: set.to_string(),
+            offset: 0,
+            speed: 1.,
+            facing: None,
+            clip: clip.to_string(),
+            clips: HashMap::new(),
+            playback: default(),
+        }
+    }
+
+    pub fn with_offset(mut self, offset: usize) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    /// Adds a clip just for this sprite, which takes priority over the
+    /// definition file.
+    pub fn with_clip(mut self, name: &str, clip: SpriteClip) -> Self {
+        self.clips.insert(name.to_string(), clip);
+        self
+    }
+
+    pub fn clip(&self) -> &str {
+        &self.clip
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.playback.finished
+    }
+
+    /// Switches to a clip, unless it's already playing.
+    pub fn play(&mut self, clip: &str) {
+        if self.clip != clip {
+            self.restart(clip);
+        }
+    }
+
+    /// Plays a clip from its first frame, even if it's already playing.
+    pub fn restart(&mut self, clip: &str) {
+        self.clip = clip.to_string();
+        self.playback = default();
+    }
+}
+
+/// Looks up a clip by name, preferring the sprite's own clips and any
+/// variant for the direction it's facing.
+fn find_clip<'a>(
+    clips: &'a HashMap<String, SpriteClip>,
+    definitions: Option<&'a SpriteAnimations>,
+    set: &str,
+    name: &str,
+    facing: Option<Facing>,
+) -> Option<(&'a SpriteClip, bool)> {
+    let find = |name: &str| {
+        clips
+            .get(name)
+            .or_else(|| definitions.and_then(|d| d.clip(set, name)))
+    };
+    if let Some(facing) = facing {
+        for (suffix, flip) in facing.candidates() {
+            if let Some(clip) = find(&format!("{}_{}", name, suffix)) {
+                return Some((clip, flip));
+            }
+        }
+    }
+    find(name).map(|clip| (clip, false))
+}
+
+fn animate_sprites(
+    mut sprites: Query<(Entity, &mut SpriteAnimator, &mut TextureAtlasSprite)>,
+    assets: Option<Res<LoadedAssets>>,
+    animations: Res<Assets<SpriteAnimations>>,
+    mut events: EventWriter<AnimationFrameEvent>,
+    time: Res<Time>,
+) {
+    let definitions = assets
+        .as_ref()
+        .and_then(|assets| animations.get(&assets.character_animations));
+
+    for (entity, mut animator, mut sprite) in sprites.iter_mut() {
+        let animator = &mut *animator;
+        let (clip, flip) = match find_clip(
+            &animator.clips,
+            definitions,
+            &animator.set,
+            &animator.clip,
+            animator.facing,
+        ) {
+            Some((clip, flip)) if !clip.frames.is_empty() => (clip, flip),
+            _ => continue,
+        };
+
+        let delta = time.delta_seconds() * animator.speed;
+        let entered = animator.playback.advance(clip, delta);
+        sprite.index =
+            animator.offset + clip.frames[animator.playback.position];
+        sprite.flip_x = flip;
+
+        for position in entered {
+            for (_, event) in
+                clip.events.iter().filter(|(frame, _)| *frame == position)
+            {
+                events.send(AnimationFrameEvent {
+                    entity,
+                    clip: animator.clip.clone(),
+                    event: event.clone(),
+                });
+            }
+        }
+
+        if animator.playback.finished {
+            if let Some(next) = clip.next.clone() {
+                animator.restart(&next);
+            }
+        }
+    }
+}
@@ -0,0 +1,831 @@
+use std::f32::consts::PI;
+
+use bevy::prelude::*;
+use bevy_ecs_ldtk::{prelude::FieldValue, EntityInstance};
+use heron::{prelude::*, rapier_plugin::PhysicsWorld};
+
+use crate::{
+    ink::{condition::Condition, ink_story::InkStory},
+    level::ActiveElement,
+    noise::xorshift,
+    physics::GameCollisionLayers,
+    player::PlayerControl,
+    reveal::Reveal,
+    spirit::{CanSeePlayer, Spirit},
+    states::States,
+};
+
+pub struct SteeringPlugin;
+
+impl Plugin for SteeringPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<WallAvoidanceBudget>().add_system_set(
+            SystemSet::on_update(States::InGame)
+                .with_system(update_spirit_states)
+                .with_system(start_paths)
+                .with_system(apply_steering),
+        );
+    }
+}
+
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SpiritState {
+    Idle,
+    Curious,
+    Fleeing,
+    Revealed,
+}
+
+impl SpiritState {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "idle" => Some(Self::Idle),
+            "curious" => Some(Self::Curious),
+            "fleeing" => Some(Self::Fleeing),
+            "revealed" => Some(Self::Revealed),
+            _ => None,
+        }
+    }
+}
+
+/// Decides which state a spirit is in.
+#[derive(Component)]
+pub struct SpiritMind {
+    /// The state a spirit switches to once it can see the player.
+    pub on_sight: SpiritState,
+    /// While this story condition holds the spirit stays idle, even when it
+    /// can see the player.
+    pub calm_when: Option<Condition>,
+}
+
+#[derive(Debug, Clone)]
+pub enum SteeringBehaviour {
+    Seek,
+    Flee {
+        radius: f32,
+        acceleration: f32,
+    },
+    Orbit {
+        angular_speed: f32,
+        distance: f32,
+        phase: f32,
+    },
+    Wander {
+        radius: f32,
+        jitter: f32,
+    },
+    Patrol(Path),
+    Arrive {
+        slowing_radius: f32,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathMode {
+    Loop,
+    PingPong,
+    Once,
+}
+
+/// What gets a path spirit moving.
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathStart {
+    Immediately,
+    Sight,
+    /// When the spirit becomes active, whether it spawns that way or the
+    /// story activates it later.
+    Activation,
+}
+
+/// Waypoints a spirit walks along, waiting at each one for the matching
+/// entry in `waits`.
+#[derive(Debug, Clone)]
+pub struct Path {
+    pub points: Vec<Vec3>,
+    pub waits: Vec<f32>,
+    pub mode: PathMode,
+    pub started: bool,
+    index: usize,
+    forward: bool,
+    waited: f32,
+    finished: bool,
+}
+
+impl Path {
+    pub fn new(points: Vec<Vec3>, waits: Vec<f32>, mode: PathMode) -> Self {
+        Self {
+            points,
+            waits,
+            mode,
+            started: false,
+            index: 0,
+            forward: true,
+            waited: 0.,
+            finished: false,
+        }
+    }
+
+    fn advance(&mut self) {
+        let last = self.points.len() - 1;
+        match self.mode {
+            PathMode::Loop => self.index = (self.index + 1) % self.points.len(),
+            PathMode::Once => {
+                if self.index == last {
+                    self.finished = true;
+                } else {
+                    self.index += 1;
+                }
+            }
+            PathMode::PingPong => {
+                if last == 0 {
+                    return;
+                }
+                if self.forward && self.index == last {
+                    self.forward = false;
+                } else if !self.forward && self.index == 0 {
+                    self.forward = true;
+                }
+                if self.forward {
+                    self.index += 1;
+                } else {
+                    self.index -= 1;
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WeightedBehaviour {
+    pub state: SpiritState,
+    pub behaviour: SteeringBehaviour,
+    pub weight: f32,
+}
+
+#[derive(Component)]
+pub struct Steering {
+    pub max_acceleration: f32,
+    pub behaviours: Vec<WeightedBehaviour>,
+    wander_angle: f32,
+    /// How far wall avoidance last bent the flee direction, kept between
+    /// the spirit's turns at casting rays.
+    wall_turn: f32,
+    seed: u32,
+}
+
+impl Steering {
+    pub fn new(
+        max_acceleration: f32,
+        behaviours: Vec<WeightedBehaviour>,
+    ) -> Self {
+        Self {
+            max_acceleration,
+            behaviours,
+            wander_angle: 0.,
+            wall_turn: 0.,
+            seed: 0x9e37_79b9,
+        }
+    }
+
+    /// Seeds the steering noise from the spirit's entity, so wandering
+    /// spirits don't all follow the same path.
+    pub fn seeded(mut self, entity: Entity) -> Self {
+        self.seed = entity.id().wrapping_mul(0x9e37_79b9).max(1);
+        self
+    }
+
+    pub fn with(
+        mut self,
+        state: SpiritState,
+        behaviour: SteeringBehaviour,
+        weight: f32,
+    ) -> Self {
+        self.behaviours.push(WeightedBehaviour {
+            state,
+            behaviour,
+            weight,
+        });
+        self
+    }
+}
+
+/// Builds the steering for a spirit from its LDtk entity. The entity type
+/// picks a default set of behaviours, which the `Behaviours` field can
+/// replace with entries like `curious:orbit:1.0` or `idle:wander:0.3`.
+/// `path` holds the world positions of the entity's `Path` points.
+pub fn spirit_steering(
+    instance: &EntityInstance,
+    path: Vec<Vec3>,
+) -> Option<(Steering, SpiritMind, PathStart)> {
+    let mut max_acceleration = 95f32;
+    let mut angular_speed = 10f32;
+    let mut distance = 120f32;
+    let mut flee_radius = 300f32;
+    let mut flee_acceleration = None;
+    let mut wander_radius = 40f32;
+    let mut calm_when = None;
+    let mut custom: Option<Vec<String>> = None;
+    let mut path_mode = PathMode::Loop;
+    let mut waits = vec![];
+    let mut wait = 0f32;
+    let mut path_start = PathStart::Immediately;
+
+    for field in instance.field_instances.iter() {
+        match (field.identifier.as_str(), &field.value) {
+            ("MaxAcceleration", FieldValue::Float(Some(value))) => {
+                max_acceleration = *value;
+            }
+            ("AngularSpeed", FieldValue::Float(Some(value))) => {
+                angular_speed = *value;
+            }
+            ("TargetDistance", FieldValue::Float(Some(value))) => {
+                distance = *value;
+            }
+            ("FleeRadius", FieldValue::Float(Some(value))) => {
+                flee_radius = *value;
+            }
+            ("FleeAcceleration", FieldValue::Float(Some(value))) => {
+                flee_acceleration = Some(*value);
+            }
+            ("WanderRadius", FieldValue::Float(Some(value))) => {
+                wander_radius = *value;
+            }
+            ("CalmWhen", FieldValue::String(Some(source))) => {
+                match Condition::parse(source) {
+                    Ok(condition) => calm_when = Some(condition),
+                    Err(err) => bevy::log::error!(
+                        "Invalid spirit condition {}: {}",
+                        source,
+                        err
+                    ),
+                }
+            }
+            ("Behaviours", FieldValue::Strings(entries)) => {
+                custom = Some(entries.iter().flatten().cloned().collect());
+            }
+            ("PathMode", FieldValue::Enum(Some(mode))) => {
+                path_mode = match mode.as_str() {
+                    "PingPong" => PathMode::PingPong,
+                    "Once" => PathMode::Once,
+                    _ => PathMode::Loop,
+                };
+            }
+            ("WaitTimes", FieldValue::Floats(times)) => {
+                waits = times.iter().map(|time| time.unwrap_or(0.)).collect();
+            }
+            ("Wait", FieldValue::Float(Some(time))) => {
+                wait = *time;
+            }
+            ("StartOn", FieldValue::Enum(Some(start))) => {
+                path_start = match start.as_str() {
+                    "Sight" => PathStart::Sight,
+                    "Activation" => PathStart::Activation,
+                    _ => PathStart::Immediately,
+                };
+            }
+            _ => {}
+        }
+    }
+
+    let behaviour = |name: &str| match name {
+        "seek" => Some(SteeringBehaviour::Seek),
+        "flee" => Some(SteeringBehaviour::Flee {
+            radius: flee_radius,
+            acceleration: flee_acceleration.unwrap_or(max_acceleration),
+        }),
+        "orbit" => Some(SteeringBehaviour::Orbit {
+            angular_speed: angular_speed * PI / 180.,
+            distance,
+            phase: 0.,
+        }),
+        "wander" => Some(SteeringBehaviour::Wander {
+            radius: wander_radius,
+            jitter: 2.,
+        }),
+        "arrive" => Some(SteeringBehaviour::Arrive {
+            slowing_radius: distance,
+        }),
+        "patrol" if !path.is_empty() => {
+            let mut waits = waits.clone();
+            waits.resize(path.len(), wait);
+            Some(SteeringBehaviour::Patrol(Path::new(
+                path.clone(),
+                waits,
+                path_mode,
+            )))
+        }
+        _ => None,
+    };
+
+    let steering = Steering::new(max_acceleration, vec![]);
+    let (mut steering, on_sight) = match instance.identifier.as_str() {
+        "RandomWalkSpirit" => (
+            steering
+                .with(SpiritState::Idle, behaviour("wander")?, 0.3)
+                .with(SpiritState::Fleeing, behaviour("flee")?, 1.),
+            SpiritState::Fleeing,
+        ),
+        "CirclingSpirit" => (
+            steering.with(SpiritState::Curious, behaviour("orbit")?, 1.),
+            SpiritState::Curious,
+        ),
+        "PathSpirit" => (
+            steering.with(SpiritState::Idle, behaviour("patrol")?, 1.),
+            SpiritState::Idle,
+        ),
+        _ => return None,
+    };
+
+    if let Some(entries) = custom {
+        steering.behaviours.clear();
+        for entry in entries {
+            let mut parts = entry.split(':');
+            let parsed = match (parts.next(), parts.next(), parts.next()) {
+                (Some(state), Some(name), weight) => SpiritState::parse(state)
+                    .zip(behaviour(name))
+                    .map(|(state, behaviour)| WeightedBehaviour {
+                        state,
+                        behaviour,
+                        weight: weight
+                            .and_then(|w| w.parse().ok())
+                            .unwrap_or(1.),
+                    }),
+                _ => None,
+            };
+            match parsed {
+                Some(parsed) => steering.behaviours.push(parsed),
+                None => bevy::log::error!("Invalid behaviour {}", entry),
+            }
+        }
+    }
+
+    Some((
+        steering,
+        SpiritMind {
+            on_sight,
+            calm_when,
+        },
+        path_start,
+    ))
+}
+
+fn update_spirit_states(
+    mut spirits: Query<
+        (
+            &mut SpiritState,
+            &SpiritMind,
+            &Reveal,
+            Option<&CanSeePlayer>,
+        ),
+        With<ActiveElement>,
+    >,
+    story: Option<Res<InkStory>>,
+) {
+    for (mut state, mind, reveal, can_see) in spirits.iter_mut() {
+        let calm = match (&mind.calm_when, &story) {
+            (Some(condition), Some(story)) => condition.evaluate(story),
+            _ => false,
+        };
+        let next = if reveal.revealed {
+            SpiritState::Revealed
+        } else if can_see.is_some() && !calm {
+            mind.on_sight
+        } else {
+            SpiritState::Idle
+        };
+        if *state != next {
+            bevy::log::debug!("Spirit state {:?} -> {:?}", *state, next);
+            *state = next;
+        }
+    }
+}
+
+fn start_paths(
+    mut spirits: Query<(
+        &mut Steering,
+        &PathStart,
+        Option<&CanSeePlayer>,
+        Option<ChangeTrackers<ActiveElement>>,
+    )>,
+) {
+    for (mut steering, start, can_see, active) in spirits.iter_mut() {
+        let should_start = match start {
+            PathStart::Immediately => true,
+            PathStart::Sight => can_see.is_some(),
+            PathStart::Activation => {
+                active.map_or(false, |active| active.is_added())
+            }
+        };
+        if !should_start {
+            continue;
+        }
+        for weighted in steering.behaviours.iter_mut() {
+            if let SteeringBehaviour::Patrol(path) = &mut weighted.behaviour {
+                path.started = true;
+            }
+        }
+    }
+}
+
+struct SteeringContext<'a> {
+    position: Vec3,
+    velocity: Vec3,
+    max_speed: f32,
+    player: Vec3,
+    delta: f32,
+    /// Whether it's this spirit's turn to cast rays for wall avoidance.
+    check_walls: bool,
+    /// How far the spirit can move in a direction before hitting a wall, up
+    /// to `WALL_LOOK_AHEAD`.
+    clearance: &'a dyn Fn(Vec3) -> f32,
+}
+
+impl<'a> SteeringContext<'a> {
+    fn seek(&self, target: Vec3) -> Vec3 {
+        flat(target - self.position).normalize_or_zero() * self.max_speed
+    }
+}
+
+pub const WALL_LOOK_AHEAD: f32 = 64.;
+
+/// Caps how many spirits cast rays for wall avoidance per frame, as each
+/// check can take up to nine rays. Spirits take turns, and keep bending
+/// their direction by the last result until it's their turn again.
+pub struct WallAvoidanceBudget {
+    pub spirits_per_frame: usize,
+    next: usize,
+}
+
+impl Default for WallAvoidanceBudget {
+    fn default() -> Self {
+        Self {
+            spirits_per_frame: 2,
+            next: 0,
+        }
+    }
+}
+
+impl SteeringBehaviour {
+    /// The most force this behaviour can contribute, if it has its own limit.
+    fn max_force(&self) -> Option<f32> {
+        match self {
+            Self::Flee { acceleration, .. } => Some(*acceleration),
+            _ => None,
+        }
+    }
+}
+
+/// How far there is to move from `position` in `direction` before hitting
+/// a wall, up to `WALL_LOOK_AHEAD`.
+pub fn wall_clearance(
+    physics_world: &PhysicsWorld,
+    position: Vec3,
+    direction: Vec3,
+) -> f32 {
+    physics_world
+        .ray_cast_with_filter(
+            position,
+            direction * WALL_LOOK_AHEAD,
+            true,
+            CollisionLayers::all_groups::<GameCollisionLayers>()
+                .with_masks([GameCollisionLayers::World]),
+            |_entity| true,
+        )
+        .map_or(WALL_LOOK_AHEAD, |hit| {
+            (hit.collision_point - position).length()
+        })
+}
+
+/// Bends a direction away from walls, preferring the clearest candidate
+/// that still roughly matches the original heading. `clearance` is how far
+/// there is to move in a direction before hitting a wall, up to
+/// `WALL_LOOK_AHEAD`.
+pub fn avoid_walls(direction: Vec3, clearance: &dyn Fn(Vec3) -> f32) -> Vec3 {
+    if clearance(direction) >= WALL_LOOK_AHEAD {
+        return direction;
+    }
+    let mut best = direction;
+    let mut best_score = f32::MIN;
+    for degrees in [30f32, -30., 60., -60., 90., -90., 120., -120.] {
+        let candidate =
+            Quat::from_rotation_z(degrees.to_radians()).mul_vec3(direction);
+        let score = clearance(candidate) / WALL_LOOK_AHEAD
+            + candidate.dot(direction) * 0.5;
+        if score > best_score {
+            best = candidate;
+            best_score = score;
+        }
+    }
+    best
+}
+
+/// Spirits move on the level plane, so depth differences shouldn't affect
+/// steering.
+fn flat(vector: Vec3) -> Vec3 {
+    vector.truncate().extend(0.)
+}
+
+/// The velocity a behaviour would like the spirit to have, or `None` if it
+/// doesn't currently care.
+fn desired_velocity(
+    behaviour: &mut SteeringBehaviour,
+    steering_noise: &mut impl FnMut() -> f32,
+    wander_angle: &mut f32,
+    wall_turn: &mut f32,
+    context: &SteeringContext,
+) -> Option<Vec3> {
+    match behaviour {
+        SteeringBehaviour::Seek => Some(context.seek(context.player)),
+        SteeringBehaviour::Flee { radius, .. } => {
+            let away = flat(context.position - context.player);
+            if away.length() > *radius {
+                None
+            } else {
+                let away = away.normalize_or_zero();
+                if context.check_walls && away != Vec3::ZERO {
+                    let direction = avoid_walls(away, context.clearance);
+                    *wall_turn =
+                        away.truncate().angle_between(direction.truncate());
+                }
+                let direction =
+                    Quat::from_rotation_z(*wall_turn).mul_vec3(away);
+                Some(direction * context.max_speed)
+            }
+        }
+        SteeringBehaviour::Orbit {
+            angular_speed,
+            distance,
+            phase,
+        } => {
+            // Accumulated rather than taken from the clock, so orbits pick
+            // up where they left off after the game stops for a while
+            *phase = (*phase + *angular_speed * context.delta) % (2. * PI);
+            let target = context.player
+                + *distance * Quat::from_rotation_z(*phase).mul_vec3(Vec3::Y);
+            Some(context.seek(target))
+        }
+        SteeringBehaviour::Wander { radius, jitter } => {
+            *wander_angle += steering_noise() * *jitter;
+            let heading = context.velocity.normalize_or_zero();
+            let circle_center = context.position + heading * *radius;
+            let offset = Quat::from_rotation_z(*wander_angle).mul_vec3(Vec3::Y)
+                * *radius;
+            Some(context.seek(circle_center + offset))
+        }
+        SteeringBehaviour::Patrol(path) => {
+            if !path.started || path.points.is_empty() {
+                return None;
+            }
+            if path.finished {
+                return Some(Vec3::ZERO);
+            }
+            let target = path.points[path.index];
+            let offset = flat(target - context.position);
+            if offset.length() < 4. {
+                path.waited += context.delta;
+                if path.waited < path.waits[path.index] {
+                    return Some(Vec3::ZERO);
+                }
+                path.waited = 0.;
+                path.advance();
+                return Some(context.seek(path.points[path.index]));
+            }
+            // Slow down on approach so waypoints are actually reached
+            let speed = context.max_speed * (offset.length() / 24.).min(1.);
+            Some(offset.normalize_or_zero() * speed)
+        }
+        SteeringBehaviour::Arrive { slowing_radius } => {
+            let offset = flat(context.player - context.position);
+            let distance = offset.length();
+            let speed = if distance < *slowing_radius {
+                context.max_speed * distance / *slowing_radius
+            } else {
+                context.max_speed
+            };
+            Some(offset.normalize_or_zero() * speed)
+        }
+    }
+}
+
+fn apply_steering(
+    mut spirits: Query<
+        (
+            &Transform,
+            &Spirit,
+            &SpiritState,
+            &mut Steering,
+            &mut Velocity,
+        ),
+        (With<ActiveElement>, Without<PlayerControl>),
+    >,
+    players: Query<&Transform, With<PlayerControl>>,
+    time: Res<Time>,
+    physics_world: PhysicsWorld,
+    mut budget: ResMut<WallAvoidanceBudget>,
+) {
+    let player = match players.get_single() {
+        Ok(player) => player.translation,
+        Err(_) => return,
+    };
+    let delta = time.delta_seconds();
+    let count = spirits.iter().count();
+    if count == 0 {
+        return;
+    }
+    let first = budget.next % count;
+    budget.next = (first + budget.spirits_per_frame) % count;
+
+    for (index, (transform, spirit, state, mut steering, mut velocity)) in
+        spirits.iter_mut().enumerate()
+    {
+        let position = transform.translation;
+        let clearance = |direction: Vec3| {
+            wall_clearance(&physics_world, position, direction)
+        };
+        let context = SteeringContext {
+            position,
+            velocity: velocity.linear,
+            max_speed: spirit.0,
+            player,
+            delta,
+            check_walls: (index + count - first) % count
+                < budget.spirits_per_frame,
+            clearance: &clearance,
+        };
+
+        let Steering {
+            max_acceleration,
+            behaviours,
+            wander_angle,
+            wall_turn,
+            seed,
+        } = &mut *steering;
+        let mut noise = || xorshift(seed) * 2. - 1.;
+
+        let mut force = Vec3::ZERO;
+        let mut force_limit = None;
+        for weighted in behaviours.iter_mut().filter(|b| b.state == *state) {
+            if let Some(desired) = desired_velocity(
+                &mut weighted.behaviour,
+                &mut noise,
+                wander_angle,
+                wall_turn,
+                &context,
+            ) {
+                let limit =
+                    weighted.behaviour.max_force().unwrap_or(*max_acceleration);
+                force += ((desired - context.velocity) * weighted.weight)
+                    .clamp_length_max(limit);
+                force_limit =
+                    Some(force_limit.map_or(limit, |l: f32| l.max(limit)));
+            }
+        }
+        // With nothing steering, brake to a stop rather than drift
+        let (force, force_limit) = match force_limit {
+            Some(limit) => (force, limit),
+            None => (
+                -velocity.linear / delta.max(f32::EPSILON),
+                *max_acceleration,
+            ),
+        };
+
+        let force = force.clamp_length_max(force_limit);
+        velocity.linear = (velocity.linear + force * delta)
+            .clamp_length_max(context.max_speed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open(_direction: Vec3) -> f32 {
+        WALL_LOOK_AHEAD
+    }
+
+    fn context(delta: f32) -> SteeringContext<'static> {
+        SteeringContext {
+            position: Vec3::ZERO,
+            velocity: Vec3::ZERO,
+            max_speed: 10.,
+            player: Vec3::ZERO,
+            delta,
+            check_walls: false,
+            clearance: &open,
+        }
+    }
+
+    fn path_started(world: &World, entity: Entity) -> bool {
+        world
+            .get::<Steering>(entity)
+            .unwrap()
+            .behaviours
+            .iter()
+            .any(|weighted| match &weighted.behaviour {
+                SteeringBehaviour::Patrol(path) => path.started,
+                _ => false,
+            })
+    }
+
+    fn path_spirit(world: &mut World, start: PathStart) -> Entity {
+        let path = Path::new(vec![Vec3::ZERO], vec![0.], PathMode::Loop);
+        let steering = Steering::new(1., vec![]).with(
+            SpiritState::Idle,
+            SteeringBehaviour::Patrol(path),
+            1.,
+        );
+        world.spawn().insert(steering).insert(start).id()
+    }
+
+    #[test]
+    fn activation_paths_start_when_spawned_active() {
+        let mut world = World::default();
+        let spirit = path_spirit(&mut world, PathStart::Activation);
+        world.entity_mut(spirit).insert(ActiveElement);
+        SystemStage::single(start_paths).run(&mut world);
+        assert!(path_started(&world, spirit));
+    }
+
+    #[test]
+    fn activation_paths_wait_until_activated() {
+        let mut world = World::default();
+        let spirit = path_spirit(&mut world, PathStart::Activation);
+        let mut stage = SystemStage::single(start_paths);
+        stage.run(&mut world);
+        assert!(!path_started(&world, spirit));
+
+        world.entity_mut(spirit).insert(ActiveElement);
+        stage.run(&mut world);
+        assert!(path_started(&world, spirit));
+    }
+
+    #[test]
+    fn spirits_get_their_own_seeds() {
+        let mut world = World::default();
+        let first = world.spawn().id();
+        let second = world.spawn().id();
+        let first = Steering::new(1., vec![]).seeded(first);
+        let second = Steering::new(1., vec![]).seeded(second);
+        assert_ne!(first.seed, second.seed);
+        assert_ne!(first.seed, 0);
+    }
+
+    #[test]
+    fn orbit_phase_only_advances_with_delta() {
+        let mut orbit = SteeringBehaviour::Orbit {
+            angular_speed: 1.,
+            distance: 10.,
+            phase: 0.,
+        };
+        let mut noise = || 0.;
+        let mut steer = |orbit: &mut SteeringBehaviour, delta| {
+            desired_velocity(
+                orbit,
+                &mut noise,
+                &mut 0.,
+                &mut 0.,
+                &context(delta),
+            )
+        };
+        let before = steer(&mut orbit, 0.);
+        assert_eq!(steer(&mut orbit, 0.), before);
+        steer(&mut orbit, 0.5);
+        match orbit {
+            SteeringBehaviour::Orbit { phase, .. } => assert_eq!(phase, 0.5),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn flee_keeps_its_wall_turn_between_checks() {
+        let mut flee = SteeringBehaviour::Flee {
+            radius: 100.,
+            acceleration: 1.,
+        };
+        let mut noise = || 0.;
+        let mut wall_turn = 0.5;
+        let mut context = context(0.1);
+        context.position = Vec3::X * 10.;
+        let desired = desired_velocity(
+            &mut flee,
+            &mut noise,
+            &mut 0.,
+            &mut wall_turn,
+            &context,
+        )
+        .unwrap();
+        let expected = Quat::from_rotation_z(0.5).mul_vec3(Vec3::X) * 10.;
+        assert!((desired - expected).length() < 1e-4);
+
+        // With a clear way out, the next check straightens the spirit out
+        context.check_walls = true;
+        desired_velocity(
+            &mut flee,
+            &mut noise,
+            &mut 0.,
+            &mut wall_turn,
+            &context,
+        );
+        assert!(wall_turn.abs() < 1e-4);
+    }
+}
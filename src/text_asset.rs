@@ -0,0 +1,137 @@
+use std::{collections::HashSet, marker::PhantomData, str::FromStr};
+
+use bevy::asset::{Asset, AssetLoader, LoadedAsset};
+
+/// An asset read from one of the game's plain text formats.
+pub trait TextAsset: Asset + Sized {
+    const EXTENSIONS: &'static [&'static str];
+
+    fn parse(source: &str) -> Result<Self, String>;
+}
+
+/// Loads any `TextAsset` from its files.
+pub struct TextAssetLoader<T>(PhantomData<T>);
+
+impl<T> Default for TextAssetLoader<T> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<T: TextAsset> AssetLoader for TextAssetLoader<T> {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut bevy::asset::LoadContext,
+    ) -> bevy::utils::BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let source = std::str::from_utf8(bytes).map_err(|_| {
+                bevy::asset::Error::msg("Failed to read text from file")
+            })?;
+            let asset = T::parse(source).map_err(bevy::asset::Error::msg)?;
+            load_context.set_default_asset(LoadedAsset::new(asset));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        T::EXTENSIONS
+    }
+}
+
+/// A line of a text asset with its `#` comment and surrounding whitespace
+/// removed.
+#[derive(Debug, Clone, Copy)]
+pub struct Line<'a> {
+    /// Counted from 1, as editors show it.
+    pub number: usize,
+    pub text: &'a str,
+}
+
+impl<'a> Line<'a> {
+    /// The contents of a `[section]` header, if this line is one.
+    pub fn header(&self) -> Option<&'a str> {
+        self.text
+            .strip_prefix('[')
+            .and_then(|text| text.strip_suffix(']'))
+            .map(str::trim)
+    }
+
+    pub fn words(&self) -> Vec<&'a str> {
+        self.text.split_whitespace().collect()
+    }
+
+    /// Points an error message at this line.
+    pub fn error(&self, message: impl std::fmt::Display) -> String {
+        format!("Line {}: {}", self.number, message)
+    }
+
+    /// Parses a value, describing it as `what` if it's invalid.
+    pub fn parse<T: FromStr>(
+        &self,
+        what: &str,
+        word: &str,
+    ) -> Result<T, String> {
+        word.parse()
+            .map_err(|_| self.error(format!("Invalid {} {}", what, word)))
+    }
+}
+
+/// The lines of a text asset that have something on them. Repeating a
+/// `[section]` header is an error, so one section can't quietly replace
+/// another.
+pub fn lines<'a>(
+    source: &'a str,
+) -> impl Iterator<Item = Result<Line<'a>, String>> {
+    let mut sections = HashSet::new();
+    source
+        .lines()
+        .enumerate()
+        .map(|(index, text)| Line {
+            number: index + 1,
+            text: text.split('#').next().unwrap_or_default().trim(),
+        })
+        .filter(|line| !line.text.is_empty())
+        .map(move |line| match line.header() {
+            Some(header) if !sections.insert(header.to_string()) => {
+                Err(line.error(format!("Duplicate section [{}]", header)))
+            }
+            _ => Ok(line),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comments_and_blank_lines_are_skipped() {
+        let source = "# Comment\n\n  first # trailing\n[section]\n";
+        let lines: Vec<_> = lines(source).collect::<Result<_, _>>().unwrap();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].number, 3);
+        assert_eq!(lines[0].text, "first");
+        assert_eq!(lines[0].header(), None);
+        assert_eq!(lines[1].header(), Some("section"));
+    }
+
+    #[test]
+    fn duplicate_sections_are_errors() {
+        let source = "[a]\n[b]\n[ a ]\n";
+        let error = lines(source).find_map(Result::err);
+        assert_eq!(error.as_deref(), Some("Line 3: Duplicate section [a]"));
+    }
+
+    #[test]
+    fn invalid_values_name_the_line() {
+        let line = Line {
+            number: 4,
+            text: "fps fast",
+        };
+        assert_eq!(line.parse::<f32>("fps", "2.5"), Ok(2.5));
+        assert_eq!(
+            line.parse::<f32>("fps", "fast"),
+            Err("Line 4: Invalid fps fast".to_string())
+        );
+    }
+}
@@ -0,0 +1,307 @@
+use bevy::prelude::*;
+use bevy_ecs_ldtk::{prelude::FieldValue, EntityInstance};
+use heron::prelude::*;
+
+use crate::{
+    activation::ElementGroups,
+    ink::{condition::Condition, ink_story::InkStory},
+    interactive_narrative::SetCurrentKnotEvent,
+    level::{
+        is_building_level, ActivationEvent, ActiveElement, LevelElement,
+        LevelLookup, NamedElement, OwningLevel, SetLevelEvent,
+    },
+    physics::GameCollisionLayers,
+    sfx::PlaySoundEvent,
+    states::GameMode,
+    world_state::WorldState,
+};
+
+pub struct TriggerPlugin;
+
+impl Plugin for TriggerPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_set(
+            SystemSet::new()
+                .with_run_criteria(is_building_level)
+                .with_system(build_triggers),
+        )
+        .add_system_set(
+            SystemSet::on_update(GameMode::Exploration)
+                .with_system(run_triggers),
+        );
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerEvent {
+    Enter,
+    Exit,
+    Stay,
+}
+
+#[derive(Debug, Clone)]
+pub enum TriggerAction {
+    Knot(String),
+    Level(String),
+    Activate(bool, String),
+    /// Plays a sound from the sound bank.
+    Sound(String),
+}
+
+#[derive(Component)]
+pub struct Trigger {
+    pub event: TriggerEvent,
+    pub repeatable: bool,
+    /// Seconds the player has to stay inside before a `Stay` trigger fires,
+    /// and between repeats.
+    pub stay_time: f32,
+    pub condition: Option<Condition>,
+    pub actions: Vec<TriggerAction>,
+}
+
+#[derive(Component, Default)]
+struct TriggerOccupancy {
+    inside: bool,
+    time_inside: f32,
+    fired: bool,
+}
+
+fn build_triggers(
+    mut commands: Commands,
+    entities: Query<(Entity, &EntityInstance), Added<EntityInstance>>,
+    levels: LevelLookup,
+    world_state: Res<WorldState>,
+) {
+    for (entity, instance) in entities.iter() {
+        if instance.identifier != "Trigger" {
+            continue;
+        }
+
+        let mut circle = false;
+        let mut event = TriggerEvent::Enter;
+        let mut repeatable = false;
+        let mut stay_time = 0f32;
+        let mut condition = None;
+        let mut actions = vec![];
+        let mut active = true;
+        let mut id = None;
+        let mut groups = vec![];
+
+        for field in instance.field_instances.iter() {
+            match (field.identifier.as_str(), &field.value) {
+                ("Shape", FieldValue::Enum(Some(shape))) => {
+                    circle = shape == "Circle";
+                }
+                ("Event", FieldValue::Enum(Some(name))) => {
+                    event = match name.as_str() {
+                        "Exit" => TriggerEvent::Exit,
+                        "Stay" => TriggerEvent::Stay,
+                        _ => TriggerEvent::Enter,
+                    };
+                }
+                ("Repeatable", FieldValue::Bool(value)) => {
+                    repeatable = *value;
+                }
+                ("StayTime", FieldValue::Float(Some(time))) => {
+                    stay_time = *time;
+                }
+                ("Condition", FieldValue::String(Some(source))) => {
+                    match Condition::parse(source) {
+                        Ok(parsed) => condition = Some(parsed),
+                        Err(err) => bevy::log::error!(
+                            "Invalid trigger condition {}: {}",
+                            source,
+                            err
+                        ),
+                    }
+                }
+                ("TargetKnot", FieldValue::String(Some(knot))) => {
+                    actions.push(TriggerAction::Knot(knot.clone()));
+                }
+                ("TargetLevel", FieldValue::String(Some(level))) => {
+                    actions.push(TriggerAction::Level(level.clone()));
+                }
+                ("Activate", FieldValue::Strings(targets)) => {
+                    for target in targets.iter().flatten() {
+                        actions.push(TriggerAction::Activate(
+                            true,
+                            target.clone(),
+                        ));
+                    }
+                }
+                ("Deactivate", FieldValue::Strings(targets)) => {
+                    for target in targets.iter().flatten() {
+                        actions.push(TriggerAction::Activate(
+                            false,
+                            target.clone(),
+                        ));
+                    }
+                }
+                ("Sound", FieldValue::String(Some(sound))) => {
+                    actions.push(TriggerAction::Sound(sound.clone()));
+                }
+                ("StartEnabled", FieldValue::Bool(start_enabled)) => {
+                    active = *start_enabled;
+                }
+                ("EntityId", FieldValue::String(Some(name))) => {
+                    id = Some(name.clone());
+                }
+                ("Groups", FieldValue::Strings(names)) => {
+                    groups = names.iter().flatten().cloned().collect();
+                }
+                _ => {}
+            }
+        }
+
+        let level = levels.locate(entity).map(|(level, _)| level);
+        let fired = !repeatable
+            && match (&level, &id) {
+                (Some(level), Some(id)) => world_state
+                    .get(level, id)
+                    .map_or(false, |state| state.triggered),
+                _ => false,
+            };
+
+        let mut entity_commands = commands.entity(entity);
+        let (width, height) = (instance.width as f32, instance.height as f32);
+        entity_commands
+            .insert(LevelElement)
+            .insert(RigidBody::Sensor)
+            .insert(if circle {
+                CollisionShape::Sphere {
+                    radius: width.min(height) / 2.,
+                }
+            } else {
+                CollisionShape::Cuboid {
+                    half_extends: Vec3::new(width / 2., height / 2., 0.),
+                    border_radius: None,
+                }
+            })
+            .insert(
+                CollisionLayers::none()
+                    .with_group(GameCollisionLayers::Trigger)
+                    .with_mask(GameCollisionLayers::Player),
+            )
+            .insert(Trigger {
+                event,
+                repeatable,
+                stay_time,
+                condition,
+                actions,
+            })
+            .insert(TriggerOccupancy { fired, ..default() });
+
+        if active {
+            entity_commands.insert(ActiveElement);
+        }
+        if let Some(level) = level {
+            entity_commands.insert(OwningLevel(level));
+        }
+        if let Some(id) = id {
+            entity_commands.insert(NamedElement(id));
+        }
+        if !groups.is_empty() {
+            entity_commands.insert(ElementGroups(groups));
+        }
+    }
+}
+
+fn run_triggers(
+    mut collisions: EventReader<CollisionEvent>,
+    mut triggers: Query<(
+        Entity,
+        &Trigger,
+        &mut TriggerOccupancy,
+        Option<&ActiveElement>,
+        Option<&NamedElement>,
+        Option<&OwningLevel>,
+    )>,
+    story: Option<Res<InkStory>>,
+    time: Res<Time>,
+    mut world_state: ResMut<WorldState>,
+    mut set_level: EventWriter<SetLevelEvent>,
+    mut set_knot: EventWriter<SetCurrentKnotEvent>,
+    mut activation: EventWriter<ActivationEvent>,
+    mut sounds: EventWriter<PlaySoundEvent>,
+) {
+    let mut crossings = vec![];
+    for event in collisions.iter() {
+        let (entity_1, entity_2) = event.rigid_body_entities();
+        let (layers_1, layers_2) = event.collision_layers();
+        let trigger = if layers_1.contains_group(GameCollisionLayers::Player)
+            && layers_2.contains_group(GameCollisionLayers::Trigger)
+        {
+            entity_2
+        } else if layers_2.contains_group(GameCollisionLayers::Player)
+            && layers_1.contains_group(GameCollisionLayers::Trigger)
+        {
+            entity_1
+        } else {
+            continue;
+        };
+        crossings.push((trigger, event.is_started()));
+    }
+
+    let delta = time.delta_seconds();
+
+    for (entity, trigger, mut occupancy, active, name, level) in
+        triggers.iter_mut()
+    {
+        let mut should_fire = false;
+
+        for (_, entered) in crossings.iter().filter(|(e, _)| *e == entity) {
+            occupancy.inside = *entered;
+            occupancy.time_inside = 0.;
+            should_fire |= match trigger.event {
+                TriggerEvent::Enter => *entered,
+                TriggerEvent::Exit => !*entered,
+                TriggerEvent::Stay => false,
+            };
+        }
+
+        if trigger.event == TriggerEvent::Stay && occupancy.inside {
+            occupancy.time_inside += delta;
+            if occupancy.time_inside >= trigger.stay_time {
+                occupancy.time_inside = 0.;
+                should_fire = true;
+            }
+        }
+
+        if !should_fire || active.is_none() || occupancy.fired {
+            continue;
+        }
+        if let Some(condition) = &trigger.condition {
+            let met = story
+                .as_ref()
+                .map_or(false, |story| condition.evaluate(story));
+            if !met {
+                continue;
+            }
+        }
+
+        bevy::log::info!("Firing trigger {:?}", name.map(|name| &name.0));
+        if !trigger.repeatable {
+            occupancy.fired = true;
+        }
+        if let (Some(name), Some(level)) = (name, level) {
+            world_state.record_triggered(&level.0, &name.0);
+        }
+
+        for action in trigger.actions.iter() {
+            match action {
+                TriggerAction::Knot(knot) => {
+                    set_knot.send(SetCurrentKnotEvent(Some(knot.clone())))
+                }
+                TriggerAction::Level(level) => {
+                    set_level.send(SetLevelEvent(level.clone()))
+                }
+                TriggerAction::Activate(active, target) => {
+                    activation.send(ActivationEvent(*active, target.clone()))
+                }
+                TriggerAction::Sound(sound) => {
+                    sounds.send(PlaySoundEvent::new(sound))
+                }
+            }
+        }
+    }
+}
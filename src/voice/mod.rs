@@ -0,0 +1,353 @@
+use bevy::{asset::LoadState, prelude::*};
+use bevy_kira_audio::prelude::*;
+use leafwing_input_manager::prelude::ActionState;
+
+use crate::{
+    ink::ink_asset::InkAsset,
+    interactive_narrative::character_for_tag,
+    loading_state::LoadedAssets,
+    player::{Action, PlayerControl},
+    sfx::SoundBuses,
+    states::{GameMode, States},
+};
+
+use self::voice_asset::*;
+
+pub mod voice_asset;
+
+pub struct VoicePlugin;
+
+impl Plugin for VoicePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_asset::<VoiceManifest>()
+            .init_asset_loader::<VoiceManifestLoader>()
+            .add_event::<SpeakLinesEvent>()
+            .init_resource::<VoiceSettings>()
+            .init_resource::<VoicePlayback>()
+            .add_system_set(
+                SystemSet::on_enter(States::Menu)
+                    .with_system(validate_voice_lines),
+            )
+            .add_system_set(
+                SystemSet::on_update(GameMode::Conversation)
+                    .with_system(queue_voice_lines)
+                    .with_system(skip_voice_line.after(queue_voice_lines))
+                    .with_system(play_voice_lines.after(skip_voice_line)),
+            )
+            .add_system_set(
+                SystemSet::on_pause(GameMode::Conversation)
+                    .with_system(pause_voice),
+            )
+            .add_system_set(
+                SystemSet::on_resume(GameMode::Conversation)
+                    .with_system(resume_voice),
+            )
+            .add_system_set(
+                SystemSet::on_exit(GameMode::Conversation)
+                    .with_system(stop_voice),
+            )
+            .add_system(gate_subtitles);
+    }
+}
+
+/// The bus voice-over plays on.
+const VOICE_BUS: &str = "voice";
+
+pub struct VoiceSettings {
+    pub enabled: bool,
+    /// Hold each subtitle, and the choices after them, until the line
+    /// before has been spoken or skipped. Otherwise everything shows at once
+    /// and the voice-over plays underneath.
+    pub wait: bool,
+}
+
+impl Default for VoiceSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            wait: true,
+        }
+    }
+}
+
+/// Sent with the voice line id of each line the narrative is showing, in
+/// order.
+pub struct SpeakLinesEvent(pub Vec<String>);
+
+/// A subtitle or choice that waits for the voice-over of the lines before
+/// it, by its place among the lines.
+#[derive(Component)]
+pub struct VoiceGated(pub usize);
+
+/// The lines being spoken, and how far through them the voice-over is.
+#[derive(Default)]
+pub struct VoicePlayback {
+    clips: Vec<Option<Handle<AudioSource>>>,
+    current: usize,
+    instance: Option<Handle<AudioInstance>>,
+    wait: bool,
+}
+
+impl VoicePlayback {
+    /// Whether the narrative should hold off on choices until the lines
+    /// have been spoken.
+    pub fn is_waiting(&self) -> bool {
+        self.wait && self.current < self.clips.len()
+    }
+
+    pub fn shows(&self, index: usize) -> bool {
+        !self.wait || index <= self.current
+    }
+
+    fn stop(&mut self, instances: &mut Assets<AudioInstance>) {
+        if let Some(handle) = self.instance.take() {
+            if let Some(instance) = instances.get_mut(&handle) {
+                instance.stop(AudioTween::default());
+            }
+        }
+    }
+}
+
+/// The speaker part of a line id, from a character's display name, like
+/// `ponterson` for "Dr. Ponterson".
+pub fn speaker_key(name: Option<&str>) -> String {
+    name.and_then(|name| name.split_whitespace().last())
+        .map(|word| {
+            word.chars()
+                .filter(|c| c.is_alphanumeric())
+                .collect::<String>()
+                .to_lowercase()
+        })
+        .unwrap_or_else(|| "narrator".to_string())
+}
+
+/// The id a line is voiced under when it doesn't have a `#vo:` tag: the
+/// speaker and a hash of the text, like `cass_3f2a91c0`. Rewording a line
+/// changes its id, so it shows up as needing a new recording.
+pub fn line_id(speaker: &str, text: &str) -> String {
+    let mut hash: u32 = 0x811c_9dc5;
+    for byte in text.trim().bytes() {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+    format!("{}_{:08x}", speaker, hash)
+}
+
+fn queue_voice_lines(
+    mut events: EventReader<SpeakLinesEvent>,
+    mut playback: ResMut<VoicePlayback>,
+    mut instances: ResMut<Assets<AudioInstance>>,
+    settings: Res<VoiceSettings>,
+    assets: Res<LoadedAssets>,
+    manifests: Res<Assets<VoiceManifest>>,
+    asset_server: Res<AssetServer>,
+) {
+    let SpeakLinesEvent(lines) = match events.iter().last() {
+        Some(event) => event,
+        None => return,
+    };
+    let manifest = manifests.get(&assets.voice_lines);
+
+    playback.stop(&mut instances);
+    playback.clips = lines
+        .iter()
+        .map(|id| {
+            if !settings.enabled {
+                return None;
+            }
+            let clip = manifest.and_then(|manifest| manifest.clip(id));
+            if clip.is_none() {
+                bevy::log::debug!("No voice-over for {}", id);
+            }
+            clip.map(|file| asset_server.load(file))
+        })
+        .collect();
+    playback.current = 0;
+    playback.wait = settings.wait;
+}
+
+/// Plays each line's clip in turn, moving straight past lines that don't
+/// have one or whose clip won't load.
+fn play_voice_lines(
+    mut playback: ResMut<VoicePlayback>,
+    instances: Res<Assets<AudioInstance>>,
+    audio: Res<Audio>,
+    asset_server: Res<AssetServer>,
+    buses: Res<SoundBuses>,
+) {
+    while playback.current < playback.clips.len() {
+        if let Some(handle) = &playback.instance {
+            match instances.get(handle).map(|instance| instance.state()) {
+                Some(PlaybackState::Stopped) => {}
+                // Not started yet, or still speaking.
+                _ => return,
+            }
+            playback.instance = None;
+            playback.current += 1;
+            continue;
+        }
+
+        let source = match &playback.clips[playback.current] {
+            Some(source) => source.clone(),
+            None => {
+                playback.current += 1;
+                continue;
+            }
+        };
+        match asset_server.get_load_state(&source) {
+            LoadState::Loaded => {
+                playback.instance = Some(
+                    audio
+                        .play(source)
+                        .with_volume(buses.volume(VOICE_BUS) as f64)
+                        .handle(),
+                );
+                return;
+            }
+            LoadState::Failed => {
+                playback.current += 1;
+            }
+            _ => return,
+        }
+    }
+}
+
+fn skip_voice_line(
+    players: Query<&ActionState<Action>, With<PlayerControl>>,
+    mut playback: ResMut<VoicePlayback>,
+    mut instances: ResMut<Assets<AudioInstance>>,
+) {
+    let pressed = players
+        .iter()
+        .any(|action| action.just_pressed(Action::Interact));
+    if pressed && playback.current < playback.clips.len() {
+        playback.stop(&mut instances);
+        playback.current += 1;
+    }
+}
+
+fn pause_voice(
+    playback: Res<VoicePlayback>,
+    mut instances: ResMut<Assets<AudioInstance>>,
+) {
+    if let Some(instance) = playback
+        .instance
+        .as_ref()
+        .and_then(|h| instances.get_mut(h))
+    {
+        instance.pause(AudioTween::default());
+    }
+}
+
+fn resume_voice(
+    playback: Res<VoicePlayback>,
+    mut instances: ResMut<Assets<AudioInstance>>,
+) {
+    if let Some(instance) = playback
+        .instance
+        .as_ref()
+        .and_then(|h| instances.get_mut(h))
+    {
+        instance.resume(AudioTween::default());
+    }
+}
+
+fn stop_voice(
+    mut playback: ResMut<VoicePlayback>,
+    mut instances: ResMut<Assets<AudioInstance>>,
+) {
+    playback.stop(&mut instances);
+    *playback = VoicePlayback::default();
+}
+
+fn gate_subtitles(
+    playback: Res<VoicePlayback>,
+    mut gated: Query<(&VoiceGated, &mut Visibility)>,
+) {
+    for (VoiceGated(index), mut visibility) in gated.iter_mut() {
+        visibility.is_visible = playback.shows(*index);
+    }
+}
+
+/// Lists the lines in the story that don't have voice-over yet, once the
+/// assets have loaded. Lines with inline logic or glue can only be matched
+/// to an id while playing, so they're counted as unchecked.
+fn validate_voice_lines(
+    mut validated: Local<bool>,
+    assets: Res<LoadedAssets>,
+    inks: Res<Assets<InkAsset>>,
+    manifests: Res<Assets<VoiceManifest>>,
+) {
+    if *validated {
+        return;
+    }
+    let (ink, manifest) = match (
+        inks.get(&assets.test_ink),
+        manifests.get(&assets.voice_lines),
+    ) {
+        (Some(ink), Some(manifest)) => (ink, manifest),
+        _ => return,
+    };
+    *validated = true;
+
+    let mut speaker = speaker_key(None);
+    let mut total = 0;
+    let mut unchecked = 0;
+    let mut missing = vec![];
+    // Everything but dialogue: comments, knots, declarations, logic,
+    // diverts and choices.
+    let skip = ["//", "=", "VAR", "CONST", "INCLUDE", "~", "->", "*", "+"];
+    for line in ink.story.lines() {
+        let line = line.trim();
+        if line.is_empty() || skip.iter().any(|p| line.starts_with(p)) {
+            continue;
+        }
+        let line = line.trim_start_matches(|c: char| c == '-' || c == ' ');
+        let mut parts = line.split('#');
+        let text = parts.next().unwrap_or_default();
+        let text = text.split("->").next().unwrap_or_default().trim();
+
+        let mut voice = None;
+        for tag in parts.map(str::trim) {
+            if let Some((name, _)) = character_for_tag(tag) {
+                speaker = speaker_key(Some(name));
+            } else if let Some(id) = tag.strip_prefix("vo:") {
+                voice = Some(id.trim().to_string());
+            }
+        }
+
+        if text.is_empty() || text == "&nbsp;" {
+            continue;
+        }
+        total += 1;
+        let id = match voice {
+            Some(id) => id,
+            None if text.contains('{') || text.contains("<>") => {
+                unchecked += 1;
+                continue;
+            }
+            None => line_id(&speaker, text),
+        };
+        if manifest.clip(&id).is_none() {
+            missing.push((id, text.to_string()));
+        }
+    }
+
+    if missing.is_empty() {
+        bevy::log::info!(
+            "All {} checked lines have voice-over ({} unchecked)",
+            total - unchecked,
+            unchecked
+        );
+        return;
+    }
+    bevy::log::warn!(
+        "{} of {} lines are missing voice-over ({} unchecked)",
+        missing.len(),
+        total,
+        unchecked
+    );
+    for (id, text) in missing.iter() {
+        bevy::log::info!("Missing voice-over {}: {}", id, text);
+    }
+}
@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+
+use bevy::reflect::TypeUuid;
+
+use crate::text_asset::{lines, TextAsset, TextAssetLoader};
+
+/// The voice-over clips that have been recorded, by line id.
+///
+/// ```text
+/// ponterson_014
+/// cass_3f2a91c0 vo/cass_intro.ogg
+/// ```
+///
+/// An id on its own is expected at `vo/<id>.ogg`. Each id can only be listed
+/// once.
+#[derive(Debug, TypeUuid)]
+#[uuid = "5d2b8e41-9f6c-4a37-b0e3-7c18a4f2d965"]
+pub struct VoiceManifest {
+    pub clips: HashMap<String, String>,
+}
+
+impl TextAsset for VoiceManifest {
+    const EXTENSIONS: &'static [&'static str] = &["voice"];
+
+    fn parse(source: &str) -> Result<Self, String> {
+        let mut clips = HashMap::new();
+        for line in lines(source) {
+            let line = line?;
+            let (id, file) = match line.words()[..] {
+                [id] => (id, format!("vo/{}.ogg", id)),
+                [id, file] => (id, file.to_string()),
+                _ => {
+                    return Err(
+                        line.error("Expected a line id and optional file")
+                    )
+                }
+            };
+            if clips.insert(id.to_string(), file).is_some() {
+                return Err(line.error(format!("Duplicate line id {}", id)));
+            }
+        }
+        Ok(Self { clips })
+    }
+}
+
+impl VoiceManifest {
+    pub fn clip(&self, id: &str) -> Option<&str> {
+        self.clips.get(id).map(|file| file.as_str())
+    }
+}
+
+pub type VoiceManifestLoader = TextAssetLoader<VoiceManifest>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clips_are_parsed() {
+        let source = "\
+# Recorded so far
+ponterson_014
+cass_3f2a91c0 vo/cass_intro.ogg # retake
+";
+        let manifest = VoiceManifest::parse(source).unwrap();
+        assert_eq!(
+            manifest.clip("ponterson_014"),
+            Some("vo/ponterson_014.ogg")
+        );
+        assert_eq!(manifest.clip("cass_3f2a91c0"), Some("vo/cass_intro.ogg"));
+        assert_eq!(manifest.clip("cass_00000000"), None);
+    }
+
+    #[test]
+    fn extra_words_are_errors() {
+        let error = VoiceManifest::parse("\nponterson_014 a.ogg b.ogg");
+        assert_eq!(
+            error.unwrap_err(),
+            "Line 2: Expected a line id and optional file"
+        );
+    }
+
+    #[test]
+    fn duplicate_ids_are_errors() {
+        let error = VoiceManifest::parse("cass_1\nponterson_2\ncass_1 a.ogg");
+        assert_eq!(error.unwrap_err(), "Line 3: Duplicate line id cass_1");
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::{HashMap, HashSet};
+
+use bevy::prelude::*;
+use bevy_ecs_ldtk::prelude::*;
+use heron::prelude::*;
+
+use crate::{
+    level::is_building_level, loading_state::LoadedAssets,
+    physics::GameCollisionLayers,
+};
+
+pub struct WallsPlugin;
+
+impl Plugin for WallsPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<IntGridCollisions>().add_system_set(
+            SystemSet::new()
+                .with_run_criteria(is_building_level)
+                .with_system(build_walls),
+        );
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct IntGridCollision {
+    pub group: GameCollisionLayers,
+    pub material: PhysicMaterial,
+}
+
+/// Collision settings for each IntGrid value identifier. Values that aren't
+/// listed here, like `Ground`, don't get a collider.
+pub struct IntGridCollisions(pub HashMap<String, IntGridCollision>);
+
+impl Default for IntGridCollisions {
+    fn default() -> Self {
+        Self(HashMap::from([
+            (
+                "Wall".to_string(),
+                IntGridCollision {
+                    group: GameCollisionLayers::World,
+                    material: PhysicMaterial::default(),
+                },
+            ),
+            (
+                "woods".to_string(),
+                IntGridCollision {
+                    group: GameCollisionLayers::World,
+                    material: PhysicMaterial {
+                        restitution: 0.1,
+                        friction: 0.8,
+                        ..Default::default()
+                    },
+                },
+            ),
+        ]))
+    }
+}
+
+/// Covers a set of grid cells with as few rectangles as possible, returning
+/// the bottom left cell and size in cells of each rectangle.
+pub fn greedy_rectangles(cells: &HashSet<IVec2>) -> Vec<(IVec2, IVec2)> {
+    let mut remaining = cells.clone();
+    let mut ordered: Vec<IVec2> = cells.iter().copied().collect();
+    ordered.sort_by_key(|cell| (cell.y, cell.x));
+
+    let mut rectangles = vec![];
+    for start in ordered {
+        if !remaining.contains(&start) {
+            continue;
+        }
+
+        let mut width = 1;
+        while remaining.contains(&(start + IVec2::new(width, 0))) {
+            width += 1;
+        }
+
+        let mut height = 1;
+        while (0..width)
+            .all(|x| remaining.contains(&(start + IVec2::new(x, height))))
+        {
+            height += 1;
+        }
+
+        for y in 0..height {
+            for x in 0..width {
+                remaining.remove(&(start + IVec2::new(x, y)));
+            }
+        }
+        rectangles.push((start, IVec2::new(width, height)));
+    }
+    rectangles
+}
+
+fn build_walls(
+    mut commands: Commands,
+    collisions: Res<IntGridCollisions>,
+    assets: Res<LoadedAssets>,
+    ldtk_assets: Res<Assets<LdtkAsset>>,
+    cells: Query<(&IntGridCell, &Parent, &Transform), Added<IntGridCell>>,
+    layers: Query<&LayerMetadata>,
+) {
+    if cells.is_empty() {
+        return;
+    }
+    let ldtk = match ldtk_assets.get(&assets.test_level) {
+        Some(ldtk) => ldtk,
+        None => return,
+    };
+
+    let mut regions: HashMap<(Entity, String), (f32, HashSet<IVec2>)> =
+        HashMap::new();
+
+    for (cell, parent, transform) in cells.iter() {
+        let layer_entity = parent.get();
+        let layer = match layers.get(layer_entity) {
+            Ok(layer) => layer,
+            Err(_) => continue,
+        };
+        let value_name = ldtk
+            .project
+            .defs
+            .layers
+            .iter()
+            .find(|definition| definition.identifier == layer.identifier)
+            .and_then(|definition| {
+                definition
+                    .int_grid_values
+                    .iter()
+                    .find(|value| value.value == cell.value)
+            })
+            .and_then(|value| value.identifier.clone());
+
+        if let Some(value_name) = value_name {
+            if !collisions.0.contains_key(&value_name) {
+                continue;
+            }
+            let grid_size = layer.grid_size as f32;
+            let coords = (transform.translation.truncate() / grid_size)
+                .floor()
+                .as_ivec2();
+            regions
+                .entry((layer_entity, value_name))
+                .or_insert_with(|| (grid_size, HashSet::new()))
+                .1
+                .insert(coords);
+        }
+    }
+
+    for ((layer_entity, value_name), (grid_size, cells)) in regions {
+        let collision = collisions.0[&value_name];
+        let rectangles = greedy_rectangles(&cells);
+        bevy::log::info!(
+            "Merged {} {} cells into {} colliders",
+            cells.len(),
+            &value_name,
+            rectangles.len()
+        );
+
+        commands.entity(layer_entity).with_children(|parent| {
+            for (start, size) in rectangles {
+                let size = size.as_vec2() * grid_size;
+                let center = start.as_vec2() * grid_size + size / 2.;
+                parent
+                    .spawn_bundle(TransformBundle::from_transform(
+                        Transform::from_translation(center.extend(0.)),
+                    ))
+                    .insert(RigidBody::Static)
+                    .insert(CollisionShape::Cuboid {
+                        half_extends: (size / 2.).extend(0.),
+                        border_radius: None,
+                    })
+                    .insert(collision.material)
+                    .insert(
+                        CollisionLayers::all_masks::<GameCollisionLayers>()
+                            .with_group(collision.group),
+                    );
+            }
+        });
+    }
+}
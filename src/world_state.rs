@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+
+use crate::{
+    level::{ActiveElement, NamedElement, OwningLevel},
+    reveal::Reveal,
+    states::States,
+};
+
+pub struct WorldStatePlugin;
+
+impl Plugin for WorldStatePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<WorldState>()
+            .add_system_set(
+                SystemSet::on_enter(States::Menu)
+                    .with_system(reset_world_state),
+            )
+            .add_system_to_stage(CoreStage::PostUpdate, apply_world_state);
+    }
+}
+
+/// What has happened to a named level element since the game started.
+#[derive(Debug, Clone, Default)]
+pub struct ElementState {
+    pub active: Option<bool>,
+    pub visible: Option<bool>,
+    pub triggered: bool,
+}
+
+/// Element state that survives levels being despawned and respawned, keyed
+/// by level identifier and `EntityId`.
+#[derive(Default)]
+pub struct WorldState {
+    elements: HashMap<(String, String), ElementState>,
+}
+
+impl WorldState {
+    pub fn get(&self, level: &str, id: &str) -> Option<&ElementState> {
+        self.elements.get(&(level.to_string(), id.to_string()))
+    }
+
+    pub fn element_mut(&mut self, level: &str, id: &str) -> &mut ElementState {
+        self.elements
+            .entry((level.to_string(), id.to_string()))
+            .or_default()
+    }
+
+    pub fn record_active(&mut self, level: &str, id: &str, active: bool) {
+        self.element_mut(level, id).active = Some(active);
+    }
+
+    pub fn record_visible(&mut self, level: &str, id: &str, visible: bool) {
+        self.element_mut(level, id).visible = Some(visible);
+    }
+
+    pub fn record_triggered(&mut self, level: &str, id: &str) {
+        self.element_mut(level, id).triggered = true;
+    }
+}
+
+fn reset_world_state(mut world_state: ResMut<WorldState>) {
+    world_state.elements.clear();
+}
+
+fn apply_world_state(
+    mut commands: Commands,
+    world_state: Res<WorldState>,
+    mut elements: Query<
+        (
+            Entity,
+            &NamedElement,
+            &OwningLevel,
+            Option<&mut Visibility>,
+            Option<&mut Reveal>,
+        ),
+        Added<OwningLevel>,
+    >,
+) {
+    for (entity, name, level, visibility, reveal) in elements.iter_mut() {
+        if let Some(state) = world_state.get(&level.0, &name.0) {
+            bevy::log::info!(
+                "Restoring {} in {}: {:?}",
+                &name.0,
+                &level.0,
+                state
+            );
+            match state.active {
+                Some(true) => {
+                    commands.entity(entity).insert(ActiveElement);
+                }
+                Some(false) => {
+                    commands.entity(entity).remove::<ActiveElement>();
+                }
+                None => {}
+            }
+            match (state.visible, reveal, visibility) {
+                (Some(visible), Some(mut reveal), _) => {
+                    reveal.set_instantly(visible);
+                }
+                (Some(visible), None, Some(mut visibility)) => {
+                    visibility.is_visible = visible;
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::level::Portal;
+
+    /// Respawns a knot portal in a level that's being revisited, with the
+    /// given state recorded from the earlier visit.
+    fn reload_portal(world_state: WorldState, active: bool) -> (World, Entity) {
+        let mut world = World::default();
+        world.insert_resource(world_state);
+        let mut portal = world.spawn();
+        portal
+            .insert(NamedElement("front_desk".to_string()))
+            .insert(OwningLevel("Lobby".to_string()))
+            .insert(Portal::Knot("front_desk".to_string()));
+        if active {
+            portal.insert(ActiveElement);
+        }
+        let portal = portal.id();
+
+        SystemStage::single(apply_world_state).run(&mut world);
+        (world, portal)
+    }
+
+    #[test]
+    fn triggered_portals_stay_active_after_a_reload() {
+        let mut world_state = WorldState::default();
+        world_state.record_triggered("Lobby", "front_desk");
+
+        let (world, portal) = reload_portal(world_state, true);
+        assert!(world.get::<ActiveElement>(portal).is_some());
+    }
+
+    #[test]
+    fn deactivated_portals_stay_inactive_after_a_reload() {
+        let mut world_state = WorldState::default();
+        world_state.record_triggered("Lobby", "front_desk");
+        world_state.record_active("Lobby", "front_desk", false);
+
+        let (world, portal) = reload_portal(world_state, true);
+        assert!(world.get::<ActiveElement>(portal).is_none());
+    }
+
+    #[test]
+    fn activated_portals_come_back_active_after_a_reload() {
+        let mut world_state = WorldState::default();
+        world_state.record_active("Lobby", "front_desk", true);
+
+        let (world, portal) = reload_portal(world_state, false);
+        assert!(world.get::<ActiveElement>(portal).is_some());
+    }
+
+    #[test]
+    fn other_levels_are_left_alone() {
+        let mut world_state = WorldState::default();
+        world_state.record_active("CorpHallway", "front_desk", false);
+
+        let (world, portal) = reload_portal(world_state, true);
+        assert!(world.get::<ActiveElement>(portal).is_some());
+    }
+}